@@ -0,0 +1,240 @@
+use crate::entity::{Behaviour, Context, Entity, Event};
+use crate::map::Map;
+use crate::vec2::Vec2;
+
+pub enum State {
+    Patrol { waypoint: usize },
+    Chase,
+    Attack { cooldown: f64 },
+}
+
+pub struct Enemy {
+    pub state: State,
+    pub waypoints: Vec<Vec2<f64>>,
+    pub speed: f64,
+    pub sight_range: f64,
+    pub attack_range: f64,
+    pub attack_interval: f64,
+    pub damage: f64,
+    last_seen: Option<Vec2<f64>>,
+    path: Vec<Vec2<usize>>,
+}
+
+impl Enemy {
+    pub fn new(waypoints: Vec<Vec2<f64>>) -> Enemy {
+        Enemy {
+            state: State::Patrol { waypoint: 0 },
+            waypoints,
+            speed: 1.5,
+            sight_range: 10.0,
+            attack_range: 1.5,
+            attack_interval: 1.0,
+            damage: 10.0,
+            last_seen: None,
+            path: Vec::new(),
+        }
+    }
+
+    fn next_state(&self, entity: &Entity, sees_player: bool, player_distance: f64) -> Option<State> {
+        match &self.state {
+            State::Patrol { waypoint: _ } => {
+                if sees_player {
+                    return Some(State::Chase);
+                }
+            }
+            State::Chase => {
+                if sees_player && player_distance <= self.attack_range {
+                    return Some(State::Attack {
+                        cooldown: self.attack_interval / 2.0,
+                    });
+                }
+                let lost = match &self.last_seen {
+                    Some(last_seen) => !sees_player && (last_seen - &entity.position).length() < 0.1,
+                    None => true,
+                };
+                if lost {
+                    return Some(State::Patrol {
+                        waypoint: self.nearest_waypoint(&entity.position),
+                    });
+                }
+            }
+            State::Attack { cooldown: _ } => {
+                if !sees_player || player_distance > self.attack_range {
+                    return Some(State::Chase);
+                }
+            }
+        }
+        None
+    }
+
+    fn nearest_waypoint(&self, position: &Vec2<f64>) -> usize {
+        let mut nearest = 0;
+        for (index, waypoint) in self.waypoints.iter().enumerate() {
+            if (waypoint - position).length() < (&self.waypoints[nearest] - position).length() {
+                nearest = index;
+            }
+        }
+        nearest
+    }
+
+    // heads for the centre of the next cell on the way to goal, replanning when the goal moves to another cell
+    // None when the goal can't be reached at all, since heading straight for it would only push into a wall
+    fn steer(&mut self, world: &Map, position: &Vec2<f64>, goal: &Vec2<f64>) -> Option<Vec2<f64>> {
+        let cell = position.as_usize();
+        let goal_cell = goal.as_usize();
+        if cell == goal_cell {
+            return Some(goal.clone());
+        }
+        if self.path.last() != Some(&goal_cell) || !self.path.contains(&cell) {
+            match world.find_path(&cell, &goal_cell) {
+                Some(path) => self.path = path,
+                None => {
+                    self.path.clear();
+                    return None;
+                }
+            }
+        }
+        if let Some(walked) = self.path.iter().position(|step| *step == cell) {
+            self.path.drain(..walked);
+        }
+        match self.path.get(1) {
+            Some(next) => Some(cell_centre(next)),
+            None => Some(goal.clone()),
+        }
+    }
+
+    // an unreachable waypoint is skipped, and an unreachable player is given up on
+    fn unreachable(&mut self, position: &Vec2<f64>) {
+        match &mut self.state {
+            State::Patrol { waypoint } => *waypoint = (*waypoint + 1) % self.waypoints.len(),
+            _ => {
+                self.last_seen = None;
+                self.state = State::Patrol {
+                    waypoint: self.nearest_waypoint(position),
+                };
+            }
+        }
+    }
+
+    // slows down on approach so it doesn't overshoot the target at low frame rates
+    fn velocity_towards(&self, position: &Vec2<f64>, target: &Vec2<f64>) -> Vec2<f64> {
+        let offset = target - position;
+        let distance = offset.length();
+        if distance < 0.05 {
+            Vec2::new()
+        } else {
+            offset * (self.speed.min(distance * 10.0) / distance)
+        }
+    }
+}
+
+impl Behaviour for Enemy {
+    fn update(&mut self, entity: &mut Entity, context: &mut Context) {
+        let player = &context.camera.position;
+        let player_distance = (player - &entity.position).length();
+        // sight passes through walls with transparent texels, so enemies can spot the player through bars
+        let sees_player = player_distance <= self.sight_range
+            && context.world.line_of_sight(&entity.position, player, true);
+        if sees_player {
+            self.last_seen = Some(player.clone());
+        }
+        if let Some(state) = self.next_state(entity, sees_player, player_distance) {
+            self.state = state;
+        }
+
+        let goal = match &mut self.state {
+            State::Patrol { waypoint } => {
+                if !self.waypoints.is_empty()
+                    && (&self.waypoints[*waypoint] - &entity.position).length() < 0.1
+                {
+                    *waypoint = (*waypoint + 1) % self.waypoints.len();
+                }
+                self.waypoints.get(*waypoint).cloned()
+            }
+            State::Chase => self.last_seen.clone(),
+            State::Attack { cooldown } => {
+                *cooldown -= context.delta;
+                if *cooldown <= 0.0 {
+                    *cooldown += self.attack_interval;
+                    context.events.push(Event::Attack {
+                        damage: self.damage,
+                    });
+                }
+                None
+            }
+        };
+        let target = match goal {
+            Some(goal) => {
+                let flowing = match (&self.state, sees_player) {
+                    (State::Chase, true) => context.player_flow_field.next(context.world, &entity.position.as_usize()).map(|next| cell_centre(&next)),
+                    _ => None,
+                };
+                match flowing.or_else(|| self.steer(context.world, &entity.position, &goal)) {
+                    Some(target) => target,
+                    None => {
+                        self.unreachable(&entity.position);
+                        entity.position.clone()
+                    }
+                }
+            }
+            None => entity.position.clone(),
+        };
+        entity.velocity = self.velocity_towards(&entity.position, &target);
+    }
+
+    // getting shot gives away where the player is
+    fn damaged(&mut self, _entity: &mut Entity, source: &Vec2<f64>) {
+        self.last_seen = Some(source.clone());
+        if let State::Patrol { waypoint: _ } = self.state {
+            self.state = State::Chase;
+        }
+    }
+}
+
+fn cell_centre(cell: &Vec2<usize>) -> Vec2<f64> {
+    Vec2 {
+        x: cell.x as f64 + 0.5,
+        y: cell.y as f64 + 0.5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::map::tests::map;
+    use crate::pathfinding::FlowField;
+
+    #[test]
+    fn gives_up_on_a_player_it_cant_reach() {
+        // two rooms with no way between them
+        let world = map(&["#######", "#..#..#", "#######"]);
+        let camera = Camera {
+            position: Vec2 { x: 1.5, y: 5.5 },
+            direction: Vec2 { x: -1.0, y: 0.0 },
+            plane: Vec2 { x: 0.0, y: 0.66 },
+            height: 0.0,
+            fov: 66.0,
+            aspect: 4.0 / 3.0,
+        };
+        let flow_field = FlowField::new(&world, &camera.position.as_usize());
+        let mut entity = Entity::new(Vec2 { x: 1.5, y: 1.5 }, 0.3, None, None);
+        let mut enemy = Enemy::new(vec![Vec2 { x: 1.5, y: 2.5 }, Vec2 { x: 1.5, y: 1.5 }]);
+        // shot from the other room, so it knows where the player is without seeing them
+        enemy.damaged(&mut entity, &Vec2 { x: 1.5, y: 4.5 });
+        assert!(matches!(enemy.state, State::Chase));
+        let mut events = Vec::new();
+        let mut context = Context {
+            id: 0,
+            delta: 1.0 / 60.0,
+            world: &world,
+            camera: &camera,
+            player_flow_field: &flow_field,
+            events: &mut events,
+        };
+        enemy.update(&mut entity, &mut context);
+        assert!(matches!(enemy.state, State::Patrol { waypoint: 1 }));
+        assert!(enemy.last_seen.is_none());
+        assert_eq!(entity.velocity.length(), 0.0);
+    }
+}
@@ -1,5 +1,6 @@
+use crate::fog::Fog;
 use crate::map::{Map, MapCell};
-use crate::util::{Side, Step};
+use crate::util::{Orientation, Side, Step};
 use crate::vec2::Vec2;
 
 use minifb::{Key, Window};
@@ -11,16 +12,167 @@ pub struct Intersection {
     pub wall_offset: Vec2<f64>,
 }
 
+impl Intersection {
+    // in multiples of the ray's direction, so this is the perpendicular distance for camera rays
+    pub fn distance(&self, origin: &Vec2<f64>, direction: &Vec2<f64>) -> f64 {
+        match &self.side {
+            Side::X => {
+                ((self.map_coordinates.x as f64) - origin.x
+                    + self.wall_offset.x
+                    + ((1 - self.step.x.value()) as f64) / 2.0)
+                    / direction.x
+            }
+            Side::Y => {
+                ((self.map_coordinates.y as f64) - origin.y
+                    + self.wall_offset.y
+                    + ((1 - self.step.y.value()) as f64) / 2.0)
+                    / direction.y
+            }
+        }
+    }
+}
+
 pub struct Ray {
     pub direction: Vec2<f64>,
     pub intersections: Vec<Intersection>,
+    pub fog: Fog,
+}
+
+impl Ray {
+    // stops at the first opaque wall, or once max_distance (in multiples of direction) has been covered
+    pub fn cast(&mut self, origin: &Vec2<f64>, world: &Map, max_distance: f64) {
+        let mut side_dist = Vec2::<f64>::new();
+        let mut map = origin.as_usize();
+        let delta_dist = Vec2 {
+            x: (1.0 / self.direction.x).abs(),
+            y: (1.0 / self.direction.y).abs(),
+        };
+        let mut side: Side;
+        let step = Vec2 {
+            x: Step::from(self.direction.x < 0.0),
+            y: Step::from(self.direction.y < 0.0),
+        };
+        if self.direction.x < 0.0 {
+            side_dist.x = (origin.x - (map.x as f64)) * delta_dist.x;
+        } else {
+            side_dist.x = ((map.x as f64) + 1.0 - origin.x) * delta_dist.x;
+        }
+        if self.direction.y < 0.0 {
+            side_dist.y = (origin.y - (map.y as f64)) * delta_dist.y;
+        } else {
+            side_dist.y = ((map.y as f64) + 1.0 - origin.y) * delta_dist.y;
+        }
+
+        let scale = self.direction.length();
+        self.fog.enter(0.0, world.at(&map), scale);
+        loop {
+            let entered = side_dist.x.min(side_dist.y);
+            if entered > max_distance {
+                break;
+            }
+            if side_dist.x < side_dist.y {
+                side_dist.x += delta_dist.x;
+                match step.x {
+                    Step::Left => map.x = map.x.wrapping_sub(1),
+                    Step::Right => map.x += 1,
+                }
+                side = Side::X;
+            } else {
+                side_dist.y += delta_dist.y;
+                match step.y {
+                    Step::Left => map.y = map.y.wrapping_sub(1),
+                    Step::Right => map.y += 1,
+                }
+                side = Side::Y;
+            }
+            self.fog.enter(entered, world.at(&map), scale);
+            match world.at(&map) {
+                Some(MapCell::Wall { texture, light_level: _ }) => {
+                    self.intersections.push(Intersection {
+                        side: side.clone(),
+                        step: step.clone(),
+                        map_coordinates: map.clone(),
+                        wall_offset: Vec2 { x: 0.0, y: 0.0 },
+                    });
+                    if !texture.has_transparency {
+                        break;
+                    }
+                }
+                Some(MapCell::ThinWall {
+                    texture,
+                    orientation,
+                    offset_into_cell,
+                    ceiling_texture: _,
+                    floor_texture: _,
+                    fog: _,
+                    fog_color: _,
+                    fog_height: _,
+                    light_level: _,
+                }) => match orientation {
+                    Orientation::XAxis => {
+                        if side_dist.x - (delta_dist.x / (1.0 / offset_into_cell)) > side_dist.y
+                        {
+                            continue;
+                        } else {
+                            self.intersections.push(Intersection {
+                                side: Side::X,
+                                step: step.clone(),
+                                map_coordinates: map.clone(),
+                                wall_offset: Vec2 {
+                                    x: offset_into_cell * step.x.value() as f64,
+                                    y: 0.0,
+                                },
+                            });
+                            if !texture.has_transparency {
+                                break;
+                            }
+                        }
+                    }
+                    Orientation::YAxis => {
+                        if side_dist.y - (delta_dist.y / (1.0 / offset_into_cell)) > side_dist.x
+                        {
+                            continue;
+                        } else {
+                            self.intersections.push(Intersection {
+                                side: Side::Y,
+                                step: step.clone(),
+                                map_coordinates: map.clone(),
+                                wall_offset: Vec2 {
+                                    x: 0.0,
+                                    y: offset_into_cell * step.y.value() as f64,
+                                },
+                            });
+                            if !texture.has_transparency {
+                                break;
+                            }
+                        }
+                    }
+                },
+                Some(MapCell::Empty {
+                    ceiling_texture: _,
+                    floor_texture: _,
+                    fog: _,
+                    fog_color: _,
+                    fog_height: _,
+                    light_level: _,
+                    water: _,
+                }) => continue,
+                None => break,
+            }
+        }
+    }
 }
 
 pub struct Camera {
     pub position: Vec2<f64>,
     pub direction: Vec2<f64>,
     pub plane: Vec2<f64>,
+    // how far the eye is above the middle of a wall, in wall units, so it's the same at any resolution
     pub height: f64,
+    // degrees across a 4:3 view
+    pub fov: f64,
+    // the view's width over its height
+    pub aspect: f64,
 }
 
 impl Camera {
@@ -29,9 +181,27 @@ impl Camera {
         Ray {
             direction: &self.direction + &self.plane * camera_x,
             intersections: Vec::new(),
+            fog: Fog::new(),
         }
     }
 
+    pub fn set_fov(&mut self, degrees: f64) {
+        self.fov = degrees.clamp(30.0, 150.0);
+        self.update_plane();
+    }
+
+    // keeps the vertical field of view, so a wider view sees further to the sides
+    pub fn set_aspect(&mut self, aspect: f64) {
+        self.aspect = aspect;
+        self.update_plane();
+    }
+
+    // only the plane's length changes, so it stays at right angles to the direction
+    fn update_plane(&mut self) {
+        let half_width = (self.fov.to_radians() / 2.0).tan() * self.aspect * 3.0 / 4.0;
+        self.plane = &self.plane * (half_width * self.direction.length() / self.plane.length());
+    }
+
     pub fn update_position_with_keys(&mut self, delta: f64, window: &Window, world: &Map) {
         let move_speed = delta * 5.0;
         let rot_speed = delta * 3.0;
@@ -42,6 +212,9 @@ impl Camera {
                 floor_texture: _,
                 fog: _,
                 fog_color: _,
+                fog_height: _,
+                light_level: _,
+                water: _,
             }) = world.at(&(&self.position + &self.direction * move_speed).as_usize())
             {
                 self.position += &self.direction * move_speed;
@@ -53,6 +226,9 @@ impl Camera {
                 floor_texture: _,
                 fog: _,
                 fog_color: _,
+                fog_height: _,
+                light_level: _,
+                water: _,
             }) = world.at(&(&self.position - &self.direction * move_speed).as_usize())
             {
                 self.position -= &self.direction * move_speed;
@@ -66,6 +242,9 @@ impl Camera {
                 floor_texture: _,
                 fog: _,
                 fog_color: _,
+                fog_height: _,
+                light_level: _,
+                water: _,
             }) = world.at(&(&self.position - &direction * move_speed).as_usize())
             {
                 self.position -= &direction * (move_speed / 1.5);
@@ -79,6 +258,9 @@ impl Camera {
                 floor_texture: _,
                 fog: _,
                 fog_color: _,
+                fog_height: _,
+                light_level: _,
+                water: _,
             }) = world.at(&(&self.position - &direction * move_speed).as_usize())
             {
                 self.position -= &direction * (move_speed / 1.5);
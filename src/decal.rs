@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::texture::{Texture, Wrap};
+use crate::util::Side;
+use crate::vec2::Vec2;
+
+// which side of a cell a ray hit, so thin walls get separate front and back faces
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum Face {
+    XNegative,
+    XPositive,
+    YNegative,
+    YPositive,
+}
+
+impl Face {
+    pub fn new(side: &Side, direction: &Vec2<f64>) -> Face {
+        match side {
+            Side::X if direction.x > 0.0 => Face::XNegative,
+            Side::X => Face::XPositive,
+            Side::Y if direction.y > 0.0 => Face::YNegative,
+            Side::Y => Face::YPositive,
+        }
+    }
+}
+
+// stretches texture over the rectangle from min to max in the face's texture space, black texels are transparent
+pub struct Decal {
+    pub texture: Rc<Texture>,
+    pub min: Vec2<f64>,
+    pub max: Vec2<f64>,
+    // seconds left before it disappears, or forever if None
+    pub lifetime: Option<f64>,
+}
+
+impl Decal {
+    pub fn covers_u(&self, u: f64) -> bool {
+        u >= self.min.x && u < self.max.x
+    }
+
+    // only valid for a u this decal covers
+    pub fn sample(&self, u: f64, v: f64) -> Option<u32> {
+        if v < self.min.y || v >= self.max.y {
+            return None;
+        }
+        let color = self.texture.sample(
+            (u - self.min.x) / (self.max.x - self.min.x),
+            (v - self.min.y) / (self.max.y - self.min.y),
+            Wrap::Clamp,
+        );
+        if (color & 0x00FFFFFF) != 0 {
+            Some(color)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Decals {
+    pub max_per_face: usize,
+    faces: HashMap<(Vec2<usize>, Face), Vec<Decal>>,
+}
+
+impl Decals {
+    pub fn new(max_per_face: usize) -> Decals {
+        Decals {
+            max_per_face,
+            faces: HashMap::new(),
+        }
+    }
+
+    // once a face is full the oldest temporary decal makes way, so bullet holes can't wipe out signs
+    pub fn add(&mut self, cell: Vec2<usize>, face: Face, decal: Decal) {
+        let decals = self.faces.entry((cell, face)).or_default();
+        if decals.len() >= self.max_per_face {
+            let oldest = decals.iter().position(|decal| decal.lifetime.is_some()).unwrap_or(0);
+            decals.remove(oldest);
+        }
+        decals.push(decal);
+    }
+
+    pub fn update(&mut self, delta: f64) {
+        for decals in self.faces.values_mut() {
+            for decal in decals.iter_mut() {
+                if let Some(lifetime) = &mut decal.lifetime {
+                    *lifetime -= delta;
+                }
+            }
+            decals.retain(|decal| !matches!(decal.lifetime, Some(lifetime) if lifetime <= 0.0));
+        }
+        self.faces.retain(|_, decals| !decals.is_empty());
+    }
+
+    pub fn on(&self, cell: &Vec2<usize>, face: &Face) -> &[Decal] {
+        match self.faces.get(&(cell.clone(), face.clone())) {
+            Some(decals) => decals,
+            None => &[],
+        }
+    }
+}
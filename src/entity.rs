@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+
+use crate::camera::Camera;
+use crate::map::Map;
+use crate::pathfinding::FlowField;
+use crate::util::{Side, Sprite};
+use crate::vec2::Vec2;
+
+pub type EntityId = usize;
+
+pub trait Behaviour {
+    fn update(&mut self, entity: &mut Entity, context: &mut Context);
+
+    fn damaged(&mut self, _entity: &mut Entity, _source: &Vec2<f64>) {}
+}
+
+pub enum Event {
+    Attack { damage: f64 },
+    Pickup { id: EntityId, health: f64 },
+}
+
+pub struct Context<'a> {
+    // the entity being updated
+    pub id: EntityId,
+    pub delta: f64,
+    pub world: &'a Map,
+    pub camera: &'a Camera,
+    // leads towards the player's cell, shared by everything chasing them
+    pub player_flow_field: &'a FlowField,
+    pub events: &'a mut Vec<Event>,
+}
+
+pub struct Entity {
+    pub position: Vec2<f64>,
+    pub velocity: Vec2<f64>,
+    pub radius: f64,
+    // entities without health can't be damaged
+    pub health: Option<f64>,
+    pub sprite: Option<Sprite>,
+    behaviour: Option<Box<dyn Behaviour>>,
+}
+
+impl Entity {
+    pub fn new(
+        position: Vec2<f64>,
+        radius: f64,
+        sprite: Option<Sprite>,
+        behaviour: Option<Box<dyn Behaviour>>,
+    ) -> Entity {
+        Entity {
+            position,
+            velocity: Vec2::new(),
+            radius,
+            health: None,
+            sprite,
+            behaviour,
+        }
+    }
+}
+
+pub struct Entities {
+    slots: Vec<Option<Entity>>,
+    free: Vec<EntityId>,
+    cells: HashMap<Vec2<usize>, Vec<EntityId>>,
+    // of any entity spawned so far, since near has to look this much further to find circles reaching in from other cells
+    max_radius: f64,
+    player_flow_field: Option<FlowField>,
+}
+
+impl Entities {
+    pub fn new() -> Entities {
+        Entities {
+            slots: Vec::new(),
+            free: Vec::new(),
+            cells: HashMap::new(),
+            max_radius: 0.0,
+            player_flow_field: None,
+        }
+    }
+
+    pub fn spawn(&mut self, mut entity: Entity) -> EntityId {
+        if let Some(sprite) = &mut entity.sprite {
+            sprite.position = entity.position.clone();
+        }
+        let cell = entity.position.as_usize();
+        self.max_radius = self.max_radius.max(entity.radius);
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.slots[id] = Some(entity);
+                id
+            }
+            None => {
+                self.slots.push(Some(entity));
+                self.slots.len() - 1
+            }
+        };
+        self.cells.entry(cell).or_default().push(id);
+        id
+    }
+
+    pub fn despawn(&mut self, id: EntityId) -> Option<Entity> {
+        let entity = self.slots.get_mut(id)?.take()?;
+        self.unindex(id, &entity.position.as_usize());
+        self.free.push(id);
+        Some(entity)
+    }
+
+    // returns whether this killed the entity, which is then despawned
+    pub fn damage(&mut self, id: EntityId, amount: f64, source: &Vec2<f64>) -> bool {
+        let entity = match self.get_mut(id) {
+            Some(entity) => entity,
+            None => return false,
+        };
+        let health = match &mut entity.health {
+            Some(health) => health,
+            None => return false,
+        };
+        *health -= amount;
+        if *health <= 0.0 {
+            self.despawn(id);
+            return true;
+        }
+        if let Some(mut behaviour) = entity.behaviour.take() {
+            behaviour.damaged(entity, source);
+            entity.behaviour = Some(behaviour);
+        }
+        false
+    }
+
+    pub fn get(&self, id: EntityId) -> Option<&Entity> {
+        self.slots.get(id)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, id: EntityId) -> Option<&mut Entity> {
+        self.slots.get_mut(id)?.as_mut()
+    }
+
+    // every entity whose collision circle comes within distance of position
+    pub fn near(&self, position: &Vec2<f64>, distance: f64) -> Vec<EntityId> {
+        let mut ids = Vec::new();
+        let reach = distance + self.max_radius;
+        let start = Vec2 {
+            x: (position.x - reach).floor().max(0.0) as usize,
+            y: (position.y - reach).floor().max(0.0) as usize,
+        };
+        let end = Vec2 {
+            x: (position.x + reach).floor().max(0.0) as usize,
+            y: (position.y + reach).floor().max(0.0) as usize,
+        };
+        for x in start.x..=end.x {
+            for y in start.y..=end.y {
+                if let Some(cell) = self.cells.get(&Vec2 { x, y }) {
+                    for &id in cell {
+                        if let Some(entity) = self.get(id) {
+                            if (&entity.position - position).length() <= distance + entity.radius {
+                                ids.push(id);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        ids
+    }
+
+    pub fn update(&mut self, delta: f64, world: &Map, camera: &Camera) -> Vec<Event> {
+        let mut events = Vec::new();
+        let player_cell = camera.position.as_usize();
+        let player_flow_field = match self.player_flow_field.take() {
+            Some(flow_field) if flow_field.target == player_cell => flow_field,
+            _ => FlowField::new(world, &player_cell),
+        };
+        for id in 0..self.slots.len() {
+            let mut entity = match self.slots[id].take() {
+                Some(entity) => entity,
+                None => continue,
+            };
+            if let Some(mut behaviour) = entity.behaviour.take() {
+                behaviour.update(
+                    &mut entity,
+                    &mut Context {
+                        id,
+                        delta,
+                        world,
+                        camera,
+                        player_flow_field: &player_flow_field,
+                        events: &mut events,
+                    },
+                );
+                entity.behaviour = Some(behaviour);
+            }
+            self.max_radius = self.max_radius.max(entity.radius);
+            let old_cell = entity.position.as_usize();
+            self.move_entity(&mut entity, delta, world);
+            if let Some(sprite) = &mut entity.sprite {
+                sprite.position = entity.position.clone();
+            }
+            let new_cell = entity.position.as_usize();
+            if new_cell != old_cell {
+                self.unindex(id, &old_cell);
+                self.cells.entry(new_cell).or_default().push(id);
+            }
+            self.slots[id] = Some(entity);
+        }
+        self.player_flow_field = Some(player_flow_field);
+        events
+    }
+
+    // moves one axis at a time so entities slide along walls and each other
+    fn move_entity(&self, entity: &mut Entity, delta: f64, world: &Map) {
+        let step = &entity.velocity * delta;
+        if step.x != 0.0 {
+            let target = Vec2 {
+                x: entity.position.x + step.x,
+                y: entity.position.y,
+            };
+            let edge = Vec2 {
+                x: target.x + entity.radius * step.x.signum(),
+                y: target.y,
+            };
+            if is_walkable(world, &edge, &Side::X) && !self.blocked(entity, &target) {
+                entity.position = target;
+            }
+        }
+        if step.y != 0.0 {
+            let target = Vec2 {
+                x: entity.position.x,
+                y: entity.position.y + step.y,
+            };
+            let edge = Vec2 {
+                x: target.x,
+                y: target.y + entity.radius * step.y.signum(),
+            };
+            if is_walkable(world, &edge, &Side::Y) && !self.blocked(entity, &target) {
+                entity.position = target;
+            }
+        }
+    }
+
+    // overlapping entities may still move apart, so anything spawned on top of another doesn't get stuck
+    fn blocked(&self, entity: &Entity, target: &Vec2<f64>) -> bool {
+        self.near(target, entity.radius).into_iter().any(|id| {
+            let other = &self.get(id).unwrap().position;
+            (target - other).length() < (&entity.position - other).length()
+        })
+    }
+
+    fn unindex(&mut self, id: EntityId, cell: &Vec2<usize>) {
+        if let Some(ids) = self.cells.get_mut(cell) {
+            ids.retain(|&other| other != id);
+            if ids.is_empty() {
+                self.cells.remove(cell);
+            }
+        }
+    }
+}
+
+fn is_walkable(world: &Map, position: &Vec2<f64>, side: &Side) -> bool {
+    position.x >= 0.0 && position.y >= 0.0 && world.is_passable(&position.as_usize(), side)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f64, y: f64, radius: f64) -> Entity {
+        Entity::new(Vec2 { x, y }, radius, None, None)
+    }
+
+    #[test]
+    fn near_finds_circles_reaching_in_from_other_cells() {
+        let mut entities = Entities::new();
+        // 0.70 away and in the next cell, but its radius brings it within 0.3 + 0.45
+        let id = entities.spawn(at(6.05, 5.5, 0.45));
+        assert_eq!(entities.near(&Vec2 { x: 5.35, y: 5.5 }, 0.3), vec![id]);
+    }
+
+    #[test]
+    fn near_skips_circles_out_of_reach() {
+        let mut entities = Entities::new();
+        entities.spawn(at(6.2, 5.5, 0.45));
+        assert!(entities.near(&Vec2 { x: 5.35, y: 5.5 }, 0.3).is_empty());
+    }
+}
@@ -0,0 +1,275 @@
+use crate::map::MapCell;
+
+// a stretch of the ray through one fog volume, running until the next one starts
+struct Segment {
+    start: f64,
+    // per multiple of the ray's direction, rather than per world unit
+    density: f64,
+    color: [f64; 3],
+    height: Option<f64>,
+}
+
+impl Segment {
+    // the average density, as a fraction of the full density, along a path climbing steadily from one height to another
+    fn coverage(&self, from: f64, to: f64) -> f64 {
+        let height = match self.height {
+            Some(height) => height,
+            None => return 1.0,
+        };
+        // the integral of a density falling linearly from the floor to nothing at height
+        let integral = |h: f64| {
+            let h = h.clamp(0.0, height);
+            h - h * h / (2.0 * height)
+        };
+        if (to - from).abs() < 1e-6 {
+            return (1.0 - from.max(0.0) / height).max(0.0);
+        }
+        (integral(to) - integral(from)) / (to - from)
+    }
+}
+
+// fog gathered along a ray as it crosses cells, with distances in multiples of the ray's direction like Intersection::distance
+pub struct Fog {
+    segments: Vec<Segment>,
+}
+
+impl Fog {
+    pub fn new() -> Fog {
+        Fog {
+            segments: Vec::new(),
+        }
+    }
+
+    // scale is the length of the ray's direction, since cell densities are per world unit
+    pub fn enter(&mut self, start: f64, cell: Option<&MapCell>, scale: f64) {
+        let (density, color, height) = match cell.and_then(|cell| cell.fog()) {
+            Some((density, color, height)) => (density * scale, color, height),
+            // walls you can see through keep whatever fog was in front of them
+            None => return,
+        };
+        let color_bytes = color.to_le_bytes();
+        let color = [color_bytes[0] as f64, color_bytes[1] as f64, color_bytes[2] as f64];
+        if let Some(last) = self.segments.last() {
+            if last.density == density && last.color == color && last.height == height {
+                return;
+            }
+        }
+        self.segments.push(Segment {
+            start,
+            density,
+            color,
+            height,
+        });
+    }
+
+    // when it isn't, the fog is the same all the way up a column, so it only needs working out once
+    pub fn layered(&self) -> bool {
+        self.segments.iter().any(|segment| segment.height.is_some())
+    }
+
+    // heights are above the floor in wall units, from the eye to the point being fogged
+    pub fn factor(&self, distance: f64, eye_height: f64, height: f64) -> FogFactor {
+        if self.segments.is_empty() {
+            return FogFactor::CLEAR;
+        }
+        let (amount, color) = self.amount(distance, eye_height, height);
+        FogFactor::new(amount, color)
+    }
+
+    // how much of the colour at this point the fog hides, and the colour of all the fog in front of it blended together
+    pub fn amount(&self, distance: f64, eye_height: f64, height: f64) -> (f64, u32) {
+        let mut transmittance = 1.0;
+        let mut accumulated = [0.0; 3];
+        for (index, segment) in self.segments.iter().enumerate() {
+            if segment.start >= distance {
+                break;
+            }
+            let end = match self.segments.get(index + 1) {
+                Some(next) => next.start.min(distance),
+                None => distance,
+            };
+            let from = eye_height + (height - eye_height) * segment.start / distance;
+            let to = eye_height + (height - eye_height) * end / distance;
+            let absorbed = 1.0 - (-segment.density * (end - segment.start) * segment.coverage(from, to)).exp();
+            for (channel, fog_color) in accumulated.iter_mut().zip(segment.color) {
+                *channel += transmittance * absorbed * fog_color;
+            }
+            transmittance *= 1.0 - absorbed;
+        }
+        let amount = 1.0 - transmittance;
+        if amount <= 0.0 {
+            return (0.0, 0);
+        }
+        let fog_color = u32::from_le_bytes([
+            (accumulated[0] / amount).min(255.0) as u8,
+            (accumulated[1] / amount).min(255.0) as u8,
+            (accumulated[2] / amount).min(255.0) as u8,
+            0x00,
+        ]);
+        (amount, fog_color)
+    }
+}
+
+// the fog over a pixel, with the blend worked out as far as it can be before the colour underneath is known
+#[derive(Clone, Copy)]
+pub struct FogFactor {
+    pub amount: f64,
+    pub color: u32,
+    // how much of the colour underneath shows through
+    keep: f64,
+    // the fog colour already scaled by how much of it there is
+    add: [f64; 3],
+}
+
+impl FogFactor {
+    pub const CLEAR: FogFactor = FogFactor {
+        amount: 0.0,
+        color: 0,
+        keep: 1.0,
+        add: [0.0; 3],
+    };
+
+    fn new(amount: f64, color: u32) -> FogFactor {
+        let bytes = color.to_le_bytes();
+        FogFactor {
+            amount,
+            color,
+            keep: 1.0 - amount,
+            add: [0, 1, 2].map(|channel| bytes[channel] as f64 * amount),
+        }
+    }
+
+    // the same sums as blending the colours directly, so it comes out exactly the same
+    pub fn apply(&self, color: u32) -> u32 {
+        if self.amount == 0.0 {
+            return color;
+        }
+        let mut bytes = color.to_le_bytes();
+        for (byte, add) in bytes.iter_mut().zip(self.add) {
+            *byte = (add + *byte as f64 * self.keep) as u8;
+        }
+        u32::from_le_bytes(bytes)
+    }
+
+    // for when the one factor covers a whole column, so the sums are done once per channel value instead of per pixel
+    pub fn table(&self) -> BlendTable {
+        let mut kept = [0.0; 256];
+        for (value, kept) in kept.iter_mut().enumerate() {
+            *kept = value as f64 * self.keep;
+        }
+        let mut channels = [[0; 256]; 3];
+        for channel in 0..3 {
+            // grey fog, black included, adds the same to every channel
+            if channel > 0 && self.add[channel] == self.add[channel - 1] {
+                channels[channel] = channels[channel - 1];
+                continue;
+            }
+            for (blended, kept) in channels[channel].iter_mut().zip(kept) {
+                *blended = (self.add[channel] + kept) as u8;
+            }
+        }
+        BlendTable { channels }
+    }
+
+    // what the palette mode wants instead
+    pub fn amount_and_color(&self) -> (f64, u32) {
+        (self.amount, self.color)
+    }
+}
+
+// a FogFactor with every channel value already blended, so it gives exactly what apply would
+pub struct BlendTable {
+    channels: [[u8; 256]; 3],
+}
+
+impl BlendTable {
+    pub fn apply(&self, color: u32) -> u32 {
+        let mut bytes = color.to_le_bytes();
+        for (byte, channel) in bytes.iter_mut().zip(&self.channels) {
+            *byte = channel[*byte as usize];
+        }
+        u32::from_le_bytes(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::texture::Texture;
+
+    fn cell(fog: f64, fog_color: u32, fog_height: Option<f64>) -> MapCell {
+        let texture = Rc::new(Texture::new(1, 1, vec![0x00808080]));
+        MapCell::Empty { ceiling_texture: texture.clone(), floor_texture: texture, fog, fog_color, fog_height, light_level: 255, water: false }
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn denser_cell_part_way_along() {
+        let mut fog = Fog::new();
+        fog.enter(0.0, Some(&cell(0.1, 0x000000FF, None)), 2.0);
+        fog.enter(3.0, Some(&cell(0.5, 0x0000FF00, None)), 2.0);
+        // densities are per world unit, so a direction twice as long doubles them
+        let (amount, color) = fog.amount(2.0, 0.5, 0.0);
+        assert!(close(amount, 1.0 - (-0.2 * 2.0f64).exp()));
+        assert_eq!(color, 0x000000FF);
+        let (amount, color) = fog.amount(5.0, 0.5, 0.0);
+        let first = 1.0 - (-0.2 * 3.0f64).exp();
+        let second = 1.0 - (-2.0f64).exp();
+        assert!(close(amount, 1.0 - (1.0 - first) * (1.0 - second)));
+        // blended by how much of the fog in front each colour makes up
+        let bytes = color.to_le_bytes();
+        assert_eq!(bytes[0], (255.0 * first / amount) as u8);
+        assert_eq!(bytes[1], (255.0 * (1.0 - first) * second / amount) as u8);
+        // walls leave the fog in front of them alone
+        fog.enter(5.0, None, 2.0);
+        assert_eq!(fog.segments.len(), 2);
+    }
+
+    #[test]
+    fn identical_cells_share_a_segment() {
+        let mut fog = Fog::new();
+        fog.enter(0.0, Some(&cell(0.1, 0x00808080, None)), 1.0);
+        fog.enter(1.0, Some(&cell(0.1, 0x00808080, None)), 1.0);
+        fog.enter(2.0, Some(&cell(0.1, 0x00808080, None)), 1.0);
+        assert_eq!(fog.segments.len(), 1);
+        fog.enter(3.0, Some(&cell(0.1, 0x00808080, Some(0.5))), 1.0);
+        fog.enter(4.0, Some(&cell(0.1, 0x00808080, None)), 1.0);
+        assert_eq!(fog.segments.len(), 3);
+        assert!(fog.layered());
+    }
+
+    #[test]
+    fn layered_fog_leaves_the_ceiling_clear() {
+        let mut fog = Fog::new();
+        fog.enter(0.0, Some(&cell(0.5, 0x00808080, Some(0.4))), 1.0);
+        assert!(fog.layered());
+        // the eye sits above the layer, so looking up never passes through any of it
+        assert_eq!(fog.amount(3.0, 0.5, 1.0), (0.0, 0));
+        assert_eq!(fog.factor(3.0, 0.5, 1.0).apply(0x00123456), 0x00123456);
+        let (to_floor, _) = fog.amount(3.0, 0.5, 0.0);
+        assert!(to_floor > 0.0);
+        // thickest at the floor, thinning out to nothing at the top of the layer
+        let segment = &fog.segments[0];
+        assert!(close(segment.coverage(0.0, 0.0), 1.0));
+        assert!(close(segment.coverage(0.2, 0.2), 0.5));
+        assert_eq!(segment.coverage(0.4, 1.0), 0.0);
+        assert!(close(segment.coverage(0.0, 0.4), 0.5));
+    }
+
+    #[test]
+    fn table_blends_exactly_like_apply() {
+        for (amount, color) in [(0.0, 0x00000000), (0.3, 0x00000000), (0.37, 0x00808080), (0.61, 0x00FF4010), (1.0, 0x0020A0FF)] {
+            let factor = FogFactor::new(amount, color);
+            let table = factor.table();
+            for value in 0..=255u32 {
+                let color = value | (255 - value) << 8 | (value * 7 % 256) << 16;
+                assert_eq!(table.apply(color), factor.apply(color));
+            }
+        }
+    }
+}
@@ -1,14 +1,45 @@
 use crate::texture::Font;
 use crate::map::{MapCell, Map};
-use crate::camera::{Ray, Camera};
+use crate::camera::{Intersection, Ray, Camera};
+use crate::decal::{Decals, Face};
+use crate::fog::{BlendTable, FogFactor};
+use crate::light::{self, LightMap};
+use crate::palette::Palette;
+use crate::particle::Particles;
+use crate::resolution::{Upscale, Viewport};
+use crate::texture::{fixed, Filter, Texture, Wrap};
 use crate::util::{Side, Sprite};
 use crate::vec2::Vec2;
 
+use std::rc::Rc;
+
+// rows transposed at a time, so the strip being written stays in cache while the columns are read down
+const TRANSPOSE_ROWS: usize = 16;
+
+pub enum Layout {
+    RowMajor,
+    // each column is contiguous, so walls and sprites write straight down memory
+    ColumnMajor,
+}
+
 pub struct Framebuffer {
     pub height: usize,
     pub width: usize,
+    // the finished frame, always row by row, filled in by present
     pub pixels: Vec<u32>,
+    // what everything is drawn into, laid out as layout says
+    target: Vec<u32>,
+    // in the same layout as target
     pub z_buffer: Vec<f64>,
+    pub layout: Layout,
+    // draws with 256 colours and colormaps when set
+    pub palette: Option<Rc<Palette>>,
+    // samples smaller copies of wall, floor and ceiling textures further away, so they don't shimmer
+    pub mipmapping: bool,
+    // for walls, floors, ceilings and sprites whose textures don't pick their own
+    pub filter: Filter,
+    // steps texture coordinates in 16.16 fixed point in the inner loops, rather than working each out in f64
+    pub fixed_point: bool,
 }
 
 impl Framebuffer {
@@ -17,12 +48,79 @@ impl Framebuffer {
             height,
             width,
             pixels: vec![0; height * width],
+            target: vec![0; height * width],
             z_buffer: vec![f64::INFINITY; height * width],
+            layout: Layout::ColumnMajor,
+            palette: None,
+            mipmapping: true,
+            filter: Filter::Nearest,
+            fixed_point: true,
+        }
+    }
+
+    pub fn resize(&mut self, height: usize, width: usize) {
+        self.height = height;
+        self.width = width;
+        self.pixels = vec![0; height * width];
+        self.target = vec![0; height * width];
+        self.z_buffer = vec![f64::INFINITY; height * width];
+    }
+
+    // copies the frame onto a window sized buffer
+    // anything in the target outside the viewport is left black
+    pub fn upscale(&self, target: &mut [u32], target_width: usize, viewport: &Viewport, upscale: &Upscale) {
+        let (scaled_width, scaled_height) = match upscale {
+            Upscale::Nearest => (viewport.width, viewport.height),
+            Upscale::Integer => {
+                let factor = (viewport.width / self.width).min(viewport.height / self.height).max(1);
+                ((self.width * factor).min(viewport.width), (self.height * factor).min(viewport.height))
+            }
+        };
+        let left = viewport.left + (viewport.width - scaled_width) / 2;
+        let top = viewport.top + (viewport.height - scaled_height) / 2;
+        let columns: Vec<usize> = (0..scaled_width).map(|x| x * self.width / scaled_width).collect();
+        for pixel in target.iter_mut() {
+            *pixel = 0;
+        }
+        for y in 0..scaled_height {
+            let source = &self.pixels[(y * self.height / scaled_height) * self.width..][..self.width];
+            let row = &mut target[(top + y) * target_width + left..][..scaled_width];
+            for (pixel, column) in row.iter_mut().zip(&columns) {
+                *pixel = source[*column];
+            }
+        }
+    }
+
+    // copies what's been drawn into pixels, ready for post processing and upscaling
+    pub fn present(&mut self) {
+        match self.layout {
+            Layout::RowMajor => self.pixels.copy_from_slice(&self.target),
+            Layout::ColumnMajor => {
+                for top in (0..self.height).step_by(TRANSPOSE_ROWS) {
+                    let rows = TRANSPOSE_ROWS.min(self.height - top);
+                    let strip = &mut self.pixels[top * self.width..][..rows * self.width];
+                    for (x, column) in self.target.chunks_exact(self.height).enumerate() {
+                        for (row, &pixel) in column[top..top + rows].iter().enumerate() {
+                            strip[row * self.width + x] = pixel;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // where pixel x, y lives in target and z_buffer; callers keep x and y on screen, since a column-major
+    // index past the right edge runs off the end rather than into the next row
+    fn index(&self, x: usize, y: usize) -> usize {
+        debug_assert!(x < self.width && y < self.height);
+        match self.layout {
+            Layout::RowMajor => y * self.width + x,
+            Layout::ColumnMajor => x * self.height + y,
         }
     }
 
     pub fn clear(&mut self) {
-        for pixel in &mut self.pixels {
+        for pixel in &mut self.target {
             *pixel = 0;
         }
     }
@@ -33,18 +131,38 @@ impl Framebuffer {
         }
     }
 
+    // how many pixels tall a wall is at a distance of one, so that pixels stay square whatever the field of view
+    pub fn projection(&self, camera: &Camera) -> f64 {
+        self.width as f64 * camera.direction.length() / (2.0 * camera.plane.length())
+    }
+
+    // heights above the floor in wall units, where 1.0 is the top of a wall
+    fn eye_height(&self, camera: &Camera) -> f64 {
+        0.5 + camera.height
+    }
+
+    // of whatever is drawn at row y, distance in front of the camera
+    fn height_at(&self, camera: &Camera, y: usize, distance: f64) -> f64 {
+        0.5 + camera.height - (y as f64 - self.height as f64 / 2.0) * distance / self.projection(camera)
+    }
+
     pub fn draw_vertical_line(&mut self, x: usize, start: usize, stop: usize, color: u32) {
         for row in start..stop {
-            self.pixels[row * self.width + x] = color;
+            let index = self.index(x, row);
+            self.target[index] = color;
         }
     }
 
+    // lines that don't fit below are skipped, and anything past the right edge is cut off
     pub fn write_ascii_string(&mut self, x: usize, y: usize, string: &Vec<u8>, font: &Font, color: u32) {
+        if y + font.glyph_size > self.height {
+            return;
+        }
         for char_index in 0..string.len() {
             for glyph_x in 0..font.glyph_size {
                 for glyph_y in 0..font.glyph_size {
                     if font.glyphs[(font.glyph_size - 1 - glyph_y) * (font.charset_length * font.glyph_size) + glyph_x + font.glyph_size * (string[char_index] as usize)] {
-                        self.pixels[((y + glyph_y) * self.width) +  x + (char_index * font.glyph_size) + glyph_x] = color;
+                        self.set_pixel(x + (char_index * font.glyph_size) + glyph_x, y + glyph_y, color);
                     }
                 }
             }
@@ -52,183 +170,405 @@ impl Framebuffer {
     }
 
     pub fn set_pixel(&mut self, x: usize, y: usize, color: u32) {
-        if let Some(pixel) = self.pixels.get_mut(y * self.width + x) {
-            *pixel = color;
+        if x < self.width && y < self.height {
+            let index = self.index(x, y);
+            self.target[index] = color;
+        }
+    }
+
+    // fog that's the same all the way down a column is blended through a table, except in the palette mode which blends its own way
+    fn column_table(&self, column_fog: &Option<FogFactor>) -> Option<BlendTable> {
+        match (&self.palette, column_fog) {
+            (None, Some(fog)) if fog.amount > 0.0 => Some(fog.table()),
+            _ => None,
         }
     }
 
-    pub fn draw_wall(&mut self, camera: &Camera, x: usize, perp_wall_dist: f64, cell: &MapCell, side: &Side, ray: &Ray, world: &Map) {
-        let line_height = (self.height as f64 / perp_wall_dist) as i32;
+    pub fn draw_wall(&mut self, camera: &Camera, x: usize, perp_wall_dist: f64, intersection: &Intersection, ray: &Ray, world: &Map, decals: &Decals, lights: &LightMap) {
+        let cell = match world.at(&intersection.map_coordinates) {
+            Some(cell) => cell,
+            None => return,
+        };
+        let side = &intersection.side;
+        let line_height = (self.projection(camera) / perp_wall_dist) as i32;
         if line_height < 0 {
             self.draw_vertical_line(x, 0, self.height, 0x00FF0000);
             return;
         }
-        let draw_start = ((-line_height / 2 + (self.height as i32) / 2) - 1 + ((camera.height / perp_wall_dist) as i32)).max(0);
-        let draw_end = ((line_height / 2 + (self.height as i32) / 2) + 1 + ((camera.height / perp_wall_dist) as i32)).min(self.height as i32);
+        // how far down the screen the wall moves as the eye rises
+        let eye_offset = camera.height * self.projection(camera) / perp_wall_dist;
+        let draw_start = ((-line_height / 2 + (self.height as i32) / 2) - 1 + (eye_offset as i32)).max(0);
+        let draw_end = ((line_height / 2 + (self.height as i32) / 2) + 1 + (eye_offset as i32)).min(self.height as i32);
 
         match cell {
-            MapCell::Wall { texture } | MapCell::ThinWall { texture, orientation: _, offset_into_cell: _, ceiling_texture: _, floor_texture: _ } => {
+            MapCell::Wall { texture, light_level: _ } | MapCell::ThinWall { texture, orientation: _, offset_into_cell: _, ceiling_texture: _, floor_texture: _, fog: _, fog_color: _, fog_height: _, light_level: _ } => {
             let wall_x = match &side {
                 Side::X => camera.position.y + perp_wall_dist * ray.direction.y,
                 Side::Y=> camera.position.x + perp_wall_dist * ray.direction.x,
             }.fract();
 
-            let mut tex_x = (wall_x * (texture.width as f64)) as usize;
-            if let Side::X = side {
-                if ray.direction.x > 0.0 {
-                    tex_x = texture.width - tex_x - 1;
-                }
-            } else {
-                if ray.direction.y < 0.0 {
-                    tex_x = texture.width - tex_x - 1;
-                }
-            }
+            let u = match side {
+                Side::X if ray.direction.x > 0.0 => 1.0 - wall_x,
+                Side::Y if ray.direction.y < 0.0 => 1.0 - wall_x,
+                _ => wall_x,
+            };
+            let column_decals: Vec<_> = decals.on(&intersection.map_coordinates, &Face::new(side, &ray.direction)).iter().filter(|decal| decal.covers_u(u)).collect();
+            // just in front of the wall, so it isn't lit from inside the cell
+            let light = light::dim(
+                &lights.at(&(&camera.position + &ray.direction * (perp_wall_dist - 0.01 / ray.direction.length()))),
+                lights.light_levels.at(cell.light_level(), lights.light_levels.distance_step(perp_wall_dist)),
+            );
+            // the palette mode darkens the other side through the light rather than the colour
+            let palette_light = match side {
+                Side::X => light,
+                Side::Y => light::dim(&light, 128),
+            };
+            let eye_height = self.eye_height(camera);
+            let column_fog = if ray.fog.layered() { None } else { Some(ray.fog.factor(perp_wall_dist, eye_height, 0.5)) };
+            let column_table = self.column_table(&column_fog);
             let step = (texture.height as f64) / (line_height as f64);
-            let mut tex_position = ((draw_start as f64) - (camera.height / perp_wall_dist) - (self.height as f64) / 2.0 + (line_height as f64) / 2.0) * step;
+            // step is how many texels each pixel covers
+            let level = if self.mipmapping { texture.mip(step) } else { texture };
+            let filter = texture.filter.unwrap_or(self.filter);
+            let mut tex_position = ((draw_start as f64) - eye_offset - (self.height as f64) / 2.0 + (line_height as f64) / 2.0) * step;
+            // the same in the mip level's texels
+            let level_scale = level.height as f64 / texture.height as f64;
+            let level_x = fixed(u * level.width as f64);
+            let mut level_y = fixed(tex_position * level_scale);
+            let level_step = fixed(step * level_scale);
             for y in draw_start..draw_end {
-                let tex_y = (tex_position as usize) & (texture.height - 1);
+                let tex_y = if self.fixed_point { level_y } else { fixed(tex_position * level_scale) };
                 tex_position += step;
-                if perp_wall_dist < self.z_buffer[y as usize * self.width + x as usize] {
-                    let mut color = texture.data[texture.height * tex_y + tex_x];
+                level_y += level_step;
+                let index = self.index(x, y as usize);
+                if perp_wall_dist < self.z_buffer[index] {
+                    let mut color = level.filtered_fixed(level_x, tex_y, Wrap::Repeat, filter);
                     if (color & 0x00FFFFFF) != 0 {
-                        if let Side::Y = side {
-                            color = (color >> 1) & 8355711;
-                        }
-                        if let Some(MapCell::Empty { ceiling_texture: _, floor_texture: _, fog, fog_color }) = world.at(&camera.position.as_usize()) {
-                            let fog_prop = (perp_wall_dist * fog).min(1.0);
-                            if fog_prop > 0.0 {
-                                let mut color_bytes = color.to_le_bytes();
-                                let fog_bytes = fog_color.to_le_bytes();
-                                color_bytes[0] = (fog_bytes[0] as f64 * fog_prop + color_bytes[0] as f64 * (1.0 - fog_prop)) as u8;
-                                color_bytes[1] = (fog_bytes[1] as f64 * fog_prop + color_bytes[1] as f64 * (1.0 - fog_prop)) as u8;
-                                color_bytes[2] = (fog_bytes[2] as f64 * fog_prop + color_bytes[2] as f64 * (1.0 - fog_prop)) as u8;
-                                color = u32::from_le_bytes(color_bytes);
+                        let decal_color = column_decals.iter().rev().find_map(|decal| decal.sample(u, tex_y as f64 / (level.height << 16) as f64));
+                        let fog = column_fog.unwrap_or_else(|| ray.fog.factor(perp_wall_dist, eye_height, self.height_at(camera, y as usize, perp_wall_dist)));
+                        color = match &self.palette {
+                            Some(palette) => {
+                                let index = match decal_color {
+                                    Some(decal_color) => palette.nearest(decal_color),
+                                    None => match filter {
+                                        Filter::Nearest => palette.texel(level, level_x, tex_y, Wrap::Repeat),
+                                        Filter::Bilinear => palette.nearest(color),
+                                    },
+                                };
+                                palette.color(index, &palette_light, fog.amount_and_color())
                             }
-                        }
-                        self.set_pixel(x, y as usize, color);
-                        self.z_buffer[y as usize * self.width + x as usize] = perp_wall_dist;
+                            None => {
+                                if let Some(decal_color) = decal_color {
+                                    color = decal_color;
+                                }
+                                if let Side::Y = side {
+                                    color = (color >> 1) & 8355711;
+                                }
+                                let color = light::shade(color, &light);
+                                match &column_table {
+                                    Some(table) => table.apply(color),
+                                    None => fog.apply(color),
+                                }
+                            }
+                        };
+                        self.target[index] = color;
+                        self.z_buffer[index] = perp_wall_dist;
                     }
                 }
             }
             },
-            MapCell::Empty { ceiling_texture: _, floor_texture: _, fog: _, fog_color: _ } => {},
+            MapCell::Empty { ceiling_texture: _, floor_texture: _, fog: _, fog_color: _, fog_height: _, light_level: _, water: _ } => {},
         }
     }
 
-    pub fn draw_sprites(&mut self, camera: &Camera, sprites: &mut Vec<Sprite>, world: &Map) {
-        for sprite in sprites.into_iter() {
-            sprite.distance_from_camera = (&camera.position - &sprite.position).length();
+    // black texels are transparent, as with sprites
+    pub fn draw_overlay(&mut self, texture: &Texture, x: i32, y: i32, scale: usize) {
+        for tex_y in 0..texture.height {
+            for tex_x in 0..texture.width {
+                let color = texture.texel(tex_x, tex_y);
+                if (color & 0x00FFFFFF) == 0 {
+                    continue;
+                }
+                for offset_y in 0..scale {
+                    for offset_x in 0..scale {
+                        let screen_x = x + (tex_x * scale + offset_x) as i32;
+                        let screen_y = y + (tex_y * scale + offset_y) as i32;
+                        if screen_x >= 0 && screen_x < self.width as i32 && screen_y >= 0 && screen_y < self.height as i32 {
+                            let index = self.index(screen_x as usize, screen_y as usize);
+                            self.target[index] = color;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn draw_sprite(&mut self, camera: &Camera, sprite: &mut Sprite, world: &Map, lights: &LightMap, rays: &[Ray]) {
+        sprite.distance_from_camera = (&camera.position - &sprite.position).length();
+        let light = match world.at(&sprite.position.as_usize()) {
+            Some(cell) => light::dim(
+                &lights.at(&sprite.position),
+                lights.light_levels.at(cell.light_level(), lights.light_levels.distance_step(sprite.distance_from_camera)),
+            ),
+            None => lights.at(&sprite.position),
+        };
+        let rel_position = &sprite.position - &camera.position;
+        let inverse_det = 1.0 / (camera.plane.x * camera.direction.y - camera.direction.x * camera.plane.y);
+        let transform = Vec2 {
+            x: inverse_det * (camera.direction.y * rel_position.x - camera.direction.x * rel_position.y),
+            y: inverse_det * (-camera.plane.y * rel_position.x + camera.plane.x * rel_position.y)
+        };
+        if transform.y == 0.0 {
+            return;
         }
-        for sprite in sprites {
-            let rel_position = &sprite.position - &camera.position;
-            let inverse_det = 1.0 / (camera.plane.x * camera.direction.y - camera.direction.x * camera.plane.y);
+        let eye_height = self.eye_height(camera);
+        let projection = self.projection(camera);
+        let vertical_offset = ((sprite.vertical_offset + camera.height) * projection / transform.y) as i32;
+        let sprite_screen_x = ((self.width as f64 / 2.0) * (1.0 + transform.x / transform.y)) as i32;
+        let sprite_height = (((projection / transform.y) as i32).abs() as f64 * sprite.scale_factor.y) as i32;
+        let sprite_width = (((projection / transform.y) as i32).abs() as f64 * sprite.scale_factor.x) as i32;
+        let draw_start = Vec2 {
+            x: ((-sprite_width / 2) + sprite_screen_x).max(0),
+            y: ((-sprite_height / 2 + (self.height as i32) / 2) + vertical_offset).max(0),
+        };
+        let draw_end = Vec2 {
+            x: ((sprite_width / 2) + sprite_screen_x).min(self.width as i32),
+            y: ((sprite_height / 2 + (self.height as i32) / 2) + vertical_offset).min(self.height as i32),
+        };
+        let filter = sprite.texture.filter.unwrap_or(self.filter);
+        let texture = &sprite.texture;
+        let top = vertical_offset + (self.height as i32) / 2 - sprite_height / 2;
+        let tex_step = fixed(texture.height as f64 / sprite_height as f64);
+        for column in draw_start.x..draw_end.x {
+            let u = ((column - (-sprite_width / 2 + sprite_screen_x)) as f64 + 0.5) / sprite_width as f64;
+            let tex_x = fixed(u * texture.width as f64);
+            let mut stepped_y = fixed((draw_start.y - top) as f64 + 0.5) * texture.height as i64 / sprite_height.max(1) as i64;
+            if transform.y > 0.0 && column >= 0 && column < self.width as i32 {
+                let ray_fog = &rays[column as usize].fog;
+                let column_fog = if ray_fog.layered() { None } else { Some(ray_fog.factor(transform.y, eye_height, 0.5)) };
+                let column_table = self.column_table(&column_fog);
+                for y in draw_start.y..draw_end.y {
+                    let tex_y = if self.fixed_point {
+                        stepped_y
+                    } else {
+                        fixed(((y - top) as f64 + 0.5) / sprite_height as f64 * texture.height as f64)
+                    };
+                    stepped_y += tex_step;
+                    let mut color = texture.filtered_fixed(tex_x, tex_y, Wrap::Clamp, filter);
+                    if (color & 0x00FFFFFF) != 0 {
+                        let index = self.index(column as usize, y as usize);
+                        if let Some(&depth) = self.z_buffer.get(index) {
+                            if sprite.distance_from_camera < depth {
+                                let fog = column_fog.unwrap_or_else(|| ray_fog.factor(transform.y, eye_height, self.height_at(camera, y as usize, transform.y)));
+                                color = match &self.palette {
+                                    Some(palette) => {
+                                        let index = match filter {
+                                            Filter::Nearest => palette.texel(texture, tex_x, tex_y, Wrap::Clamp),
+                                            Filter::Bilinear => palette.nearest(color),
+                                        };
+                                        palette.color(index, &light, fog.amount_and_color())
+                                    }
+                                    None => {
+                                        let color = light::shade(color, &light);
+                                        match &column_table {
+                                            Some(table) => table.apply(color),
+                                            None => fog.apply(color),
+                                        }
+                                    }
+                                };
+                                self.target[index] = color;
+                                self.z_buffer[index] = sprite.distance_from_camera;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // flat squares depth tested against what's already drawn, without writing depth themselves
+    pub fn draw_particles(&mut self, camera: &Camera, particles: &Particles, rays: &[Ray]) {
+        let inverse_det = 1.0 / (camera.plane.x * camera.direction.y - camera.direction.x * camera.plane.y);
+        let eye_height = self.eye_height(camera);
+        let projection = self.projection(camera);
+        for particle in &particles.particles {
+            let rel_position = &particle.position - &camera.position;
             let transform = Vec2 {
                 x: inverse_det * (camera.direction.y * rel_position.x - camera.direction.x * rel_position.y),
                 y: inverse_det * (-camera.plane.y * rel_position.x + camera.plane.x * rel_position.y)
             };
-            if transform.y == 0.0 {
+            if transform.y <= 0.05 {
                 continue;
             }
-            let vertical_offset = ((sprite.vertical_offset / transform.y) + (camera.height / transform.y)) as i32;
-            let sprite_screen_x = ((self.width as f64 / 2.0) * (1.0 + transform.x / transform.y)) as i32;
-            let sprite_height = (((self.height as f64 / transform.y) as i32).abs() as f64 * sprite.scale_factor.y) as i32;
-            let sprite_width = (((self.height as f64 / transform.y) as i32).abs() as f64 * sprite.scale_factor.x) as i32;
-            let draw_start = Vec2 {
-                x: ((-sprite_width / 2) + sprite_screen_x).max(0),
-                y: ((-sprite_height / 2 + (self.height as i32) / 2) + vertical_offset).max(0),
-            };
-            let draw_end = Vec2 {
-                x: ((sprite_width / 2) + sprite_screen_x).min(self.width as i32),
-                y: ((sprite_height / 2 + (self.height as i32) / 2) + vertical_offset).min(self.height as i32),
-            };
-            for column in draw_start.x..draw_end.x {
-                let tex_x = (256 * (column - (-sprite_width / 2 + (sprite_screen_x))) * sprite.texture.width as i32 / sprite_width) as i32 / 256;
-                if transform.y > 0.0 && column >= 0 && column < self.width as i32 {
-                    for y in draw_start.y..draw_end.y {
-                        let d = (y - vertical_offset) * 256 - self.height as i32 * 128 + sprite_height * 128;
-                        let tex_y = ((d * sprite.texture.height as i32) / sprite_height) / 256;
-                        if (tex_x as usize) < sprite.texture.width && (tex_y as usize) < sprite.texture.height {
-                            let mut color = sprite.texture.data[sprite.texture.width * tex_y as usize + tex_x as usize];
-                            if (color & 0x00FFFFFF) != 0 {
-                                if let Some(&depth) = self.z_buffer.get(y as usize * self.width + column as usize) {
-                                    if sprite.distance_from_camera < depth {
-                        if let Some(MapCell::Empty { ceiling_texture: _, floor_texture: _, fog, fog_color }) = world.at(&camera.position.as_usize()) {
-                            let fog_prop = (sprite.distance_from_camera * fog).min(1.0);
-                            if fog_prop > 0.0 {
-                                let mut color_bytes = color.to_le_bytes();
-                                let fog_bytes = fog_color.to_le_bytes();
-                                color_bytes[0] = (fog_bytes[0] as f64 * fog_prop + color_bytes[0] as f64 * (1.0 - fog_prop)) as u8;
-                                color_bytes[1] = (fog_bytes[1] as f64 * fog_prop + color_bytes[1] as f64 * (1.0 - fog_prop)) as u8;
-                                color_bytes[2] = (fog_bytes[2] as f64 * fog_prop + color_bytes[2] as f64 * (1.0 - fog_prop)) as u8;
-                                color = u32::from_le_bytes(color_bytes);
-                            }
-                        }
-                                        self.set_pixel(column as usize, y as usize, color);
-                                        self.z_buffer[y as usize * self.width + column as usize] = sprite.distance_from_camera;
-                                    }
-                                }
-                            }
-                        }
+            let screen_x = ((self.width as f64 / 2.0) * (1.0 + transform.x / transform.y)) as i32;
+            let screen_y = (self.height as f64 / 2.0 + (camera.height + 0.5 - particle.height) * projection / transform.y) as i32;
+            let size = ((particle.size() * projection / transform.y) as i32).max(1);
+            let fog = rays[screen_x.clamp(0, self.width as i32 - 1) as usize].fog.factor(transform.y, eye_height, particle.height);
+            let mut color = fog.apply(particle.color());
+            if let Some(palette) = &self.palette {
+                color = palette.colors[palette.nearest(color) as usize];
+            }
+            for y in (screen_y - size / 2).max(0)..(screen_y - size / 2 + size).min(self.height as i32) {
+                for x in (screen_x - size / 2).max(0)..(screen_x - size / 2 + size).min(self.width as i32) {
+                    let index = self.index(x as usize, y as usize);
+                    if transform.y < self.z_buffer[index] {
+                        self.target[index] = color;
                     }
                 }
             }
         }
     }
 
-    pub fn draw_floor_and_ceiling(&mut self, camera: &Camera, world: &Map) {
-        for y in 0..self.height {
-            let is_floor = y > self.height / 2;
-            let ray_dir_0 = &camera.direction - &camera.plane;
-            let ray_dir_1 = &camera.direction + &camera.plane;
-
-            let current_position = if is_floor { 
-                (y as i32) - (self.height as i32) / 2
-            } else {
-                 (self.height as i32) / 2 - (y as i32)
-            };
-            let camera_z = if is_floor {
-                0.5 * self.height as f64 + camera.height
-            } else {
-                0.5 * self.height as f64 - camera.height
-            };
-            let row_distance = camera_z / current_position as f64;
-
-            let floor_step = row_distance * (&ray_dir_1 - &ray_dir_0) / self.width as f64;
-            let mut floor = &camera.position + row_distance * &ray_dir_0;
-
-            for x in 0..self.width {
-                let cell = floor.as_usize();
-                floor += &floor_step;
-                match world.at(&cell) {
-                    Some(MapCell::Empty { ceiling_texture, floor_texture, fog: _, fog_color: _ }) | Some(MapCell::ThinWall { texture: _, orientation: _, offset_into_cell: _, ceiling_texture, floor_texture }) => {
-                    let texture_coords = Vec2 {
-                        // this is also the ceiling size, but we only check the floor size
-                        x: ((floor_texture.width as f64 * (floor.x - cell.x as f64)) as usize & (floor_texture.width - 1)) as i32,
-                        y: ((floor_texture.height as f64 * (floor.y - cell.y as f64)) as usize & (floor_texture.height - 1)) as i32,
-                    };
+    pub fn draw_floor_and_ceiling(&mut self, camera: &Camera, world: &Map, lights: &LightMap, rays: &[Ray]) {
+        let eye_height = self.eye_height(camera);
+        let projection = self.projection(camera);
+        let ray_dir_0 = &camera.direction - &camera.plane;
+        let ray_dir_1 = &camera.direction + &camera.plane;
+        // everything that only changes from row to row, so the pixels can be walked in whichever order suits the layout
+        // each row steps along its own position, since either order still visits a row from left to right
+        let mut rows: Vec<FloorRow> = (0..self.height)
+            .map(|y| {
+                let is_floor = y > self.height / 2;
+                let current_position = if is_floor {
+                    (y as i32) - (self.height as i32) / 2
+                } else {
+                     (self.height as i32) / 2 - (y as i32)
+                };
+                let camera_z = if is_floor {
+                    (0.5 + camera.height) * projection
+                } else {
+                    (0.5 - camera.height) * projection
+                };
+                let row_distance = camera_z / current_position as f64;
+                let floor_step = row_distance * (&ray_dir_1 - &ray_dir_0) / self.width as f64;
+                let floor = &camera.position + row_distance * &ray_dir_0;
+                FloorRow {
+                    is_floor,
+                    height: if is_floor { 0.0 } else { 1.0 },
+                    row_distance,
+                    distance_step: lights.light_levels.distance_step(row_distance),
+                    // how far apart neighbouring pixels land on the floor, across and between rows
+                    footprint: floor_step.length().max(row_distance / current_position as f64),
+                    fixed_floor: (fixed(floor.x), fixed(floor.y)),
+                    fixed_step: (fixed(floor_step.x), fixed(floor_step.y)),
+                    floor,
+                    floor_step,
+                }
+            })
+            .collect();
+        let (outer, inner) = match self.layout {
+            Layout::RowMajor => (self.height, self.width),
+            Layout::ColumnMajor => (self.width, self.height),
+        };
+        // neighbouring pixels are mostly over the same cell, so it's only looked up again when that changes
+        let mut current: (Vec2<usize>, Option<&MapCell>) = (Vec2 { x: usize::MAX, y: usize::MAX }, None);
 
-                    let mut color = if is_floor {
-                        (floor_texture.data[(floor_texture.width as i32 * texture_coords.y + texture_coords.x) as usize] >> 1) & 8355711
-                    } else {
-                        (ceiling_texture.data[(ceiling_texture.width as i32 * texture_coords.y + texture_coords.x) as usize] >> 1) & 8355711
+        for a in 0..outer {
+            for b in 0..inner {
+                let (x, y) = match self.layout {
+                    Layout::RowMajor => (b, a),
+                    Layout::ColumnMajor => (a, b),
+                };
+                let row = &mut rows[y];
+                // the horizon is infinitely far away, so there's no floor or ceiling to draw there
+                if !row.row_distance.is_finite() {
+                    continue;
+                }
+                let ray = &rays[x];
+                // position is in 16.16 fixed point either way
+                let (cell, position) = if self.fixed_point {
+                    let cell = Vec2 {
+                        x: (row.fixed_floor.0.max(0) >> 16) as usize,
+                        y: (row.fixed_floor.1.max(0) >> 16) as usize,
                     };
-                        if let Some(MapCell::Empty { ceiling_texture: _, floor_texture: _, fog, fog_color }) = world.at(&camera.position.as_usize()) {
-                            let fog_prop = ((&floor - &camera.position).length() * fog).min(1.0);
-                            if fog_prop > 0.0 {
-                                let mut color_bytes = color.to_le_bytes();
-                                let fog_bytes = fog_color.to_le_bytes();
-                                color_bytes[0] = (fog_bytes[0] as f64 * fog_prop + color_bytes[0] as f64 * (1.0 - fog_prop)) as u8;
-                                color_bytes[1] = (fog_bytes[1] as f64 * fog_prop + color_bytes[1] as f64 * (1.0 - fog_prop)) as u8;
-                                color_bytes[2] = (fog_bytes[2] as f64 * fog_prop + color_bytes[2] as f64 * (1.0 - fog_prop)) as u8;
-                                color = u32::from_le_bytes(color_bytes);
-                            }
+                    row.fixed_floor = (row.fixed_floor.0.wrapping_add(row.fixed_step.0), row.fixed_floor.1.wrapping_add(row.fixed_step.1));
+                    (cell, row.fixed_floor)
+                } else {
+                    let cell = row.floor.as_usize();
+                    row.floor += &row.floor_step;
+                    (cell, (fixed(row.floor.x), fixed(row.floor.y)))
+                };
+                if cell != current.0 {
+                    current = (cell.clone(), world.at(&cell));
+                }
+                match current.1 {
+                    Some(MapCell::Empty { ceiling_texture, floor_texture, fog: _, fog_color: _, fog_height: _, light_level, water: _ }) | Some(MapCell::ThinWall { texture: _, orientation: _, offset_into_cell: _, ceiling_texture, floor_texture, fog: _, fog_color: _, fog_height: _, light_level }) => {
+                    let texture = if row.is_floor { floor_texture } else { ceiling_texture };
+                    let filter = texture.filter.unwrap_or(self.filter);
+                    let texture = if self.mipmapping { texture.mip(row.footprint * texture.width as f64) } else { texture };
+                    let tex_x = position.0.wrapping_sub((cell.x as i64) << 16).wrapping_mul(texture.width as i64);
+                    let tex_y = position.1.wrapping_sub((cell.y as i64) << 16).wrapping_mul(texture.height as i64);
+                    let color = texture.filtered_fixed(tex_x, tex_y, Wrap::Repeat, filter);
+                    let light = light::dim(&lights.at_fixed(position.0, position.1), lights.light_levels.at(*light_level, row.distance_step));
+                    // every pixel has its own distance along its own ray, so there's no sharing the fog here
+                    let fog = ray.fog.factor(row.row_distance, eye_height, row.height);
+                    let index = self.index(x, y);
+                    self.target[index] = match &self.palette {
+                        Some(palette) => {
+                            let index = match filter {
+                                Filter::Nearest => palette.texel(texture, tex_x, tex_y, Wrap::Repeat),
+                                Filter::Bilinear => palette.nearest(color),
+                            };
+                            palette.color(index, &light::dim(&light, 128), fog.amount_and_color())
                         }
-                    self.pixels[y * self.width + x] = color;
+                        None => fog.apply(light::shade((color >> 1) & 8355711, &light)),
+                    };
                     },
-                    Some(MapCell::Wall { texture: _ }) => {},
+                    Some(MapCell::Wall { texture: _, light_level: _ }) => {},
                     None => {},
                 }
             }
         }
     }
 }
+
+struct FloorRow {
+    is_floor: bool,
+    // of the floor or ceiling, in wall units
+    height: f64,
+    row_distance: f64,
+    distance_step: usize,
+    footprint: f64,
+    // where the next pixel along lands, and how far each pixel moves it
+    floor: Vec2<f64>,
+    floor_step: Vec2<f64>,
+    fixed_floor: (i64, i64),
+    fixed_step: (i64, i64),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::tests::map_with_walls;
+
+    #[test]
+    fn wall_column_samples_a_tall_texture() {
+        // every texel different, and none of them black
+        let texture = Texture::new(64, 128, (0..128).flat_map(|y| (0..64).map(move |x| 0x00010000 | y << 8 | x)).collect());
+        let world = map_with_walls(&["###", "#.#", "#.#", "###"], Rc::new(texture));
+        // a projection of 64, so the wall one unit away fills the 64 rows and each row steps two texels
+        let camera = Camera {
+            position: Vec2 { x: 2.0, y: 1.5 },
+            direction: Vec2 { x: -1.0, y: 0.0 },
+            plane: Vec2 { x: 0.0, y: 0.125 },
+            height: 0.0,
+            fov: 90.0,
+            aspect: 1.0,
+        };
+        let mut framebuffer = Framebuffer::new(64, 16);
+        framebuffer.mipmapping = false;
+        let x = 8;
+        let mut ray = camera.get_ray(x, framebuffer.width);
+        ray.cast(&camera.position, &world, f64::INFINITY);
+        let intersection = ray.intersections.last().unwrap();
+        let perp_wall_dist = intersection.distance(&camera.position, &ray.direction);
+        assert!((perp_wall_dist - 1.0).abs() < 1e-9);
+        framebuffer.draw_wall(&camera, x, perp_wall_dist, intersection, &ray, &world, &Decals::new(1), &LightMap::new(&world, 1));
+        framebuffer.present();
+        // halfway across the face is column 32, and the rows run down the whole texture
+        for y in 0..64 {
+            assert_eq!(framebuffer.pixels[y * framebuffer.width + x], 0x00010000 | (2 * y as u32) << 8 | 32, "row {}", y);
+        }
+        assert_eq!(framebuffer.pixels[x - 1], 0);
+    }
+}
@@ -0,0 +1,296 @@
+use crate::map::Map;
+use crate::vec2::Vec2;
+
+pub struct PointLight {
+    pub position: Vec2<f64>,
+    pub color: u32,
+    pub radius: f64,
+    pub intensity: f64,
+    // how far the light dips when it flickers, 0 for a steady light
+    pub flicker: f64,
+}
+
+impl PointLight {
+    fn flickered_intensity(&self, time: f64, index: usize) -> f64 {
+        if self.flicker == 0.0 {
+            return self.intensity;
+        }
+        // a few sines at unrelated frequencies look random enough, and each light gets its own phase
+        let phase = index as f64 * 1.7;
+        let noise = ((time * 7.3 + phase).sin() + (time * 13.1 + phase * 2.3).sin() + (time * 23.7 + phase * 0.7).sin()) / 3.0;
+        self.intensity * (1.0 - self.flicker * (noise * 0.5 + 0.5))
+    }
+}
+
+// light multipliers in 8.8 fixed point, so 256 leaves a colour untouched
+pub type Light = [u16; 3];
+
+// the samples a light reaches and how much it falls off by each of them, kept until the light moves
+struct Reach {
+    position: Vec2<f64>,
+    radius: f64,
+    samples: Vec<(usize, f64)>,
+}
+
+// brightness for each cell light level at each distance, so shading a pixel is just a lookup
+pub struct LightLevels {
+    pub max_distance: f64,
+    pub distance_steps: usize,
+    // 8.8 fixed point, distance_steps entries for each of the 256 light levels
+    table: Vec<u16>,
+}
+
+impl LightLevels {
+    // curve takes a light level from 0 to 1 and a distance from 0 to 1, and gives the brightness there
+    pub fn new(max_distance: f64, distance_steps: usize, curve: impl Fn(f64, f64) -> f64) -> LightLevels {
+        let mut table = Vec::with_capacity(256 * distance_steps);
+        for level in 0..256 {
+            for step in 0..distance_steps {
+                let brightness = curve(level as f64 / 255.0, step as f64 / (distance_steps - 1) as f64);
+                table.push((brightness.max(0.0) * 256.0).min(u16::MAX as f64) as u16);
+            }
+        }
+        LightLevels {
+            max_distance,
+            distance_steps,
+            table,
+        }
+    }
+
+    // like Doom, darker areas fade faster, and full brightness doesn't fade at all
+    pub fn doom(max_distance: f64) -> LightLevels {
+        LightLevels::new(max_distance, 64, |level, distance| level * (1.0 - distance * (1.0 - level)))
+    }
+
+    // worked out once per column or row and shared by every pixel in it
+    pub fn distance_step(&self, distance: f64) -> usize {
+        ((distance / self.max_distance * (self.distance_steps - 1) as f64) as usize).min(self.distance_steps - 1)
+    }
+
+    pub fn at(&self, light_level: u8, distance_step: usize) -> u16 {
+        self.table[light_level as usize * self.distance_steps + distance_step]
+    }
+}
+
+pub struct LightMap {
+    pub lights: Vec<PointLight>,
+    pub ambient: f64,
+    pub light_levels: LightLevels,
+    // samples per cell along each axis
+    pub resolution: usize,
+    size: Vec2<usize>,
+    samples: Vec<Light>,
+    time: f64,
+    // what the samples were last computed from
+    computed_for: Vec<(Vec2<f64>, f64)>,
+    reach: Vec<Reach>,
+    // each sample's light before it's rounded into samples, so a light that only changes brightness can be swapped out of it
+    totals: Vec<[f64; 3]>,
+}
+
+impl LightMap {
+    pub fn new(world: &Map, resolution: usize) -> LightMap {
+        let size = world.size();
+        LightMap {
+            lights: Vec::new(),
+            ambient: 1.0,
+            light_levels: LightLevels::doom(16.0),
+            resolution,
+            samples: vec![[256; 3]; size.x * resolution * size.y * resolution],
+            size: Vec2 {
+                x: size.x * resolution,
+                y: size.y * resolution,
+            },
+            time: 0.0,
+            computed_for: Vec::new(),
+            reach: Vec::new(),
+            totals: Vec::new(),
+        }
+    }
+
+    // recomputes every sample when a light has moved, but when one has only changed brightness just its own samples are redone
+    pub fn update(&mut self, delta: f64, world: &Map) {
+        self.time += delta;
+        let state: Vec<(Vec2<f64>, f64)> = self
+            .lights
+            .iter()
+            .enumerate()
+            .map(|(index, light)| (light.position.clone(), light.flickered_intensity(self.time, index)))
+            .collect();
+        if state == self.computed_for {
+            return;
+        }
+        let moved = state.len() != self.computed_for.len()
+            || self.lights.iter().zip(&self.reach).any(|(light, reach)| reach.position != light.position || reach.radius != light.radius);
+        if moved {
+            self.recompute(&state, world);
+        } else {
+            // flickering only changes brightness, so just those lights' samples need redoing
+            for (index, (_, after)) in state.iter().enumerate() {
+                let before = self.computed_for[index].1;
+                if before != *after {
+                    self.rescale(index, before, *after);
+                }
+            }
+        }
+        self.computed_for = state;
+    }
+
+    fn rescale(&mut self, index: usize, before: f64, after: f64) {
+        let color = self.lights[index].color.to_le_bytes();
+        for (sample, falloff) in &self.reach[index].samples {
+            let total = &mut self.totals[*sample];
+            for channel in 0..3 {
+                total[channel] -= falloff * before * color[channel] as f64 / 255.0;
+                total[channel] += falloff * after * color[channel] as f64 / 255.0;
+            }
+            self.samples[*sample] = to_light(total);
+        }
+    }
+
+    fn recompute(&mut self, state: &[(Vec2<f64>, f64)], world: &Map) {
+        self.reach.truncate(self.lights.len());
+        for index in 0..self.lights.len() {
+            let light = &self.lights[index];
+            let stale = match self.reach.get(index) {
+                Some(reach) => reach.position != light.position || reach.radius != light.radius,
+                None => true,
+            };
+            if stale {
+                let reach = Reach {
+                    position: light.position.clone(),
+                    radius: light.radius,
+                    samples: self.reach_of(light, world),
+                };
+                if index < self.reach.len() {
+                    self.reach[index] = reach;
+                } else {
+                    self.reach.push(reach);
+                }
+            }
+        }
+
+        self.totals.clear();
+        self.totals.resize(self.samples.len(), [self.ambient; 3]);
+        for ((light, (_, intensity)), reach) in self.lights.iter().zip(state).zip(&self.reach) {
+            let color = light.color.to_le_bytes();
+            for (sample, falloff) in &reach.samples {
+                let total = &mut self.totals[*sample];
+                for channel in 0..3 {
+                    total[channel] += falloff * intensity * color[channel] as f64 / 255.0;
+                }
+            }
+        }
+        for (sample, total) in self.samples.iter_mut().zip(&self.totals) {
+            *sample = to_light(total);
+        }
+    }
+
+    // walls cast shadows, so a sample is only lit if there's a clear line from the light to it
+    // unlike sight, light doesn't get through the gaps in walls with transparent texels
+    fn reach_of(&self, light: &PointLight, world: &Map) -> Vec<(usize, f64)> {
+        let mut reach = Vec::new();
+        let start = self.sample_position(&(&light.position - &Vec2 { x: light.radius, y: light.radius }));
+        let end = self.sample_position(&(&light.position + &Vec2 { x: light.radius, y: light.radius }));
+        for sample_x in start.x..=end.x.min(self.size.x - 1) {
+            for sample_y in start.y..=end.y.min(self.size.y - 1) {
+                let centre = Vec2 {
+                    x: (sample_x as f64 + 0.5) / self.resolution as f64,
+                    y: (sample_y as f64 + 0.5) / self.resolution as f64,
+                };
+                let distance = (&centre - &light.position).length();
+                if distance < light.radius && world.line_of_sight(&light.position, &centre, false) {
+                    reach.push((sample_x * self.size.y + sample_y, (1.0 - distance / light.radius).powi(2)));
+                }
+            }
+        }
+        reach
+    }
+
+    fn sample_position(&self, position: &Vec2<f64>) -> Vec2<usize> {
+        Vec2 {
+            x: (position.x.max(0.0) * self.resolution as f64) as usize,
+            y: (position.y.max(0.0) * self.resolution as f64) as usize,
+        }
+    }
+
+    pub fn at(&self, position: &Vec2<f64>) -> Light {
+        self.sample(self.sample_position(position))
+    }
+
+    // for positions in 16.16 fixed point
+    pub fn at_fixed(&self, x: i64, y: i64) -> Light {
+        self.sample(Vec2 {
+            x: ((x.max(0) * self.resolution as i64) >> 16) as usize,
+            y: ((y.max(0) * self.resolution as i64) >> 16) as usize,
+        })
+    }
+
+    fn sample(&self, sample: Vec2<usize>) -> Light {
+        if sample.x < self.size.x && sample.y < self.size.y {
+            self.samples[sample.x * self.size.y + sample.y]
+        } else {
+            [256; 3]
+        }
+    }
+}
+
+fn to_light(total: &[f64; 3]) -> Light {
+    total.map(|channel| (channel * 256.0).min(u16::MAX as f64) as u16)
+}
+
+// scales a light by a cell's light level from LightLevels::at
+pub fn dim(light: &Light, level: u16) -> Light {
+    [
+        ((light[0] as u32 * level as u32) >> 8) as u16,
+        ((light[1] as u32 * level as u32) >> 8) as u16,
+        ((light[2] as u32 * level as u32) >> 8) as u16,
+    ]
+}
+
+pub fn shade(color: u32, light: &Light) -> u32 {
+    let mut color_bytes = color.to_le_bytes();
+    for channel in 0..3 {
+        color_bytes[channel] = ((color_bytes[channel] as u32 * light[channel] as u32) >> 8).min(255) as u8;
+    }
+    u32::from_le_bytes(color_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::tests::map;
+
+    fn lights(world: &Map) -> LightMap {
+        let mut lights = LightMap::new(world, 4);
+        lights.ambient = 0.2;
+        for (x, y, flicker) in [(2.5, 2.5, 0.4), (2.5, 5.5, 0.0), (3.5, 4.5, 0.7)] {
+            lights.lights.push(PointLight {
+                position: Vec2 { x, y },
+                color: 0x00FFA040,
+                radius: 3.0,
+                intensity: 1.0,
+                flicker,
+            });
+        }
+        lights
+    }
+
+    #[test]
+    fn flicker_rescales_to_the_same_samples_as_a_full_recompute() {
+        let world = map(&["########", "#......#", "#..#...#", "#......#", "#......#", "########"]);
+        let mut flickering = lights(&world);
+        for _ in 0..200 {
+            flickering.update(1.0 / 60.0, &world);
+        }
+        let mut fresh = lights(&world);
+        fresh.update(flickering.time, &world);
+        // lit well above the ambient light somewhere
+        assert!(fresh.samples.iter().any(|sample| sample[2] > 256));
+        for (rescaled, recomputed) in flickering.samples.iter().zip(&fresh.samples) {
+            for channel in 0..3 {
+                assert!((rescaled[channel] as i32 - recomputed[channel] as i32).abs() <= 1);
+            }
+        }
+    }
+}
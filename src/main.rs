@@ -1,247 +1,295 @@
+mod ai;
 mod camera;
+mod decal;
+mod entity;
+mod fog;
 mod framebuffer;
+mod light;
 mod map;
+mod palette;
+mod particle;
+mod pathfinding;
+mod pickup;
+mod postprocess;
+mod resolution;
 mod texture;
+mod tint;
 mod util;
 mod vec2;
+mod weapon;
 
-use minifb::{Window, WindowOptions};
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
 
-use camera::{Camera, Intersection};
-use framebuffer::Framebuffer;
-use map::{Map, MapCell};
-use texture::{Font, Texture};
-use util::{Orientation, Side, Sprite, Step};
+use ai::Enemy;
+use camera::{Camera, Ray};
+use decal::{Decal, Decals, Face};
+use entity::{Entities, Entity, Event};
+use framebuffer::{Framebuffer, Layout};
+use light::{LightMap, PointLight};
+use map::Map;
+use palette::Palette;
+use particle::{Emitter, Particles};
+use pickup::{medkit_texture, Pickup};
+use postprocess::{Effect, PostProcess};
+use resolution::{Aspect, DynamicResolution, Upscale};
+use texture::{Filter, Font, Texture};
+use tint::Tints;
+use util::Sprite;
 use vec2::Vec2;
+use weapon::{hitscan, Target, Weapon};
 
 use std::rc::Rc;
 use std::time::Instant;
 
+const SPRITE_DRAW_DISTANCE: f64 = 16.0;
+const WINDOW_WIDTH: usize = 800;
+const WINDOW_HEIGHT: usize = 600;
+const FIELD_OF_VIEW: f64 = 66.0;
+
 fn main() {
-    let mut framebuffer = Framebuffer::new(600, 800);
+    // renders at half the window's resolution to start with, stretched up to fill it
+    let mut resolution = DynamicResolution::new(0.5, 1.0 / 60.0);
+    let mut upscale = Upscale::Nearest;
+    let mut aspect = Aspect::HorPlus;
+    let mut window_size = (WINDOW_WIDTH, WINDOW_HEIGHT);
+    let mut viewport = aspect.viewport(WINDOW_WIDTH, WINDOW_HEIGHT);
+    let (width, height) = resolution.size(&viewport);
+    let mut framebuffer = Framebuffer::new(height, width);
+    let mut window_pixels = vec![0; WINDOW_WIDTH * WINDOW_HEIGHT];
     let mut camera = Camera {
         position: Vec2 { x: 3.0, y: 12.0 },
         direction: Vec2 { x: -1.0, y: 0.0 },
         plane: Vec2 {
             x: 0.0,
-            y: (framebuffer.width as f64 / framebuffer.height as f64) / 2.0,
+            y: (WINDOW_WIDTH as f64 / WINDOW_HEIGHT as f64) / 2.0,
         },
         height: 0.0,
+        fov: FIELD_OF_VIEW,
+        aspect: WINDOW_WIDTH as f64 / WINDOW_HEIGHT as f64,
     };
+    camera.set_fov(FIELD_OF_VIEW);
 
     let mut time = Instant::now();
     let mut old_time: Instant;
 
     let font = Font::load_from_bmp(&include_bytes!("../res/font.bmp").to_vec(), 8);
-    let textures: Vec<Rc<Texture>> = vec![
-        Rc::new(Texture::load_from_bmp(
-            &include_bytes!("../res/textures/eagle.bmp").to_vec(),
-        )),
-        Rc::new(Texture::load_from_bmp(
-            &include_bytes!("../res/textures/redbrick.bmp").to_vec(),
-        )),
-        Rc::new(Texture::load_from_bmp(
-            &include_bytes!("../res/textures/purplestone.bmp").to_vec(),
-        )),
-        Rc::new(Texture::load_from_bmp(
-            &include_bytes!("../res/textures/greystone.bmp").to_vec(),
-        )),
-        Rc::new(Texture::load_from_bmp(
-            &include_bytes!("../res/textures/bluestone.bmp").to_vec(),
-        )),
-        Rc::new(Texture::load_from_bmp(
-            &include_bytes!("../res/textures/mossy.bmp").to_vec(),
-        )),
-        Rc::new(Texture::load_from_bmp(
-            &include_bytes!("../res/textures/wood.bmp").to_vec(),
-        )),
-        Rc::new(Texture::load_from_bmp(
-            &include_bytes!("../res/textures/colorstone.bmp").to_vec(),
-        )),
-        Rc::new(Texture::load_from_bmp(
-            &include_bytes!("../res/textures/barrel.bmp").to_vec(),
-        )),
-        Rc::new(Texture::load_from_bmp(
-            &include_bytes!("../res/textures/pillar.bmp").to_vec(),
-        )),
-        Rc::new(Texture::load_from_bmp(
-            &include_bytes!("../res/textures/greenlight.bmp").to_vec(),
-        )),
+    let mut textures: Vec<Texture> = vec![
+        Texture::load_from_bmp(&include_bytes!("../res/textures/eagle.bmp").to_vec()),
+        Texture::load_from_bmp(&include_bytes!("../res/textures/redbrick.bmp").to_vec()),
+        Texture::load_from_bmp(&include_bytes!("../res/textures/purplestone.bmp").to_vec()),
+        Texture::load_from_bmp(&include_bytes!("../res/textures/greystone.bmp").to_vec()),
+        Texture::load_from_bmp(&include_bytes!("../res/textures/bluestone.bmp").to_vec()),
+        Texture::load_from_bmp(&include_bytes!("../res/textures/mossy.bmp").to_vec()),
+        Texture::load_from_bmp(&include_bytes!("../res/textures/wood.bmp").to_vec()),
+        Texture::load_from_bmp(&include_bytes!("../res/textures/colorstone.bmp").to_vec()),
+        Texture::load_from_bmp(&include_bytes!("../res/textures/barrel.bmp").to_vec()),
+        Texture::load_from_bmp(&include_bytes!("../res/textures/pillar.bmp").to_vec()),
+        Texture::load_from_bmp(&include_bytes!("../res/textures/greenlight.bmp").to_vec()),
     ];
+    let mut palette = Palette::new(&textures);
+    for texture in &mut textures {
+        palette.index(texture);
+    }
+    let textures: Vec<Rc<Texture>> = textures.into_iter().map(Rc::new).collect();
 
     let world = Map::new(&textures);
+    for fog_color in world.fog_colors() {
+        palette.add_fog_color(fog_color);
+    }
+    let palette = Rc::new(palette);
 
-    let mut sprites = vec![
-        Sprite {
+    let mut entities = Entities::new();
+    entities.spawn(Entity::new(
+        Vec2 { x: 3.0, y: 8.0 },
+        0.3,
+        Some(Sprite {
             position: Vec2 { x: 3.0, y: 8.0 },
             texture: textures[8].clone(),
             scale_factor: Vec2 { x: 1.0, y: 1.0 },
             vertical_offset: 0.0,
             distance_from_camera: 0.0,
-        },
-        Sprite {
+        }),
+        None,
+    ));
+    entities.spawn(Entity::new(
+        Vec2 { x: 3.0, y: 6.0 },
+        0.45,
+        Some(Sprite {
             position: Vec2 { x: 3.0, y: 6.0 },
             texture: textures[8].clone(),
             scale_factor: Vec2 { x: 1.5, y: 1.5 },
-            vertical_offset: -150.0,
+            vertical_offset: -0.25,
+            distance_from_camera: 0.0,
+        }),
+        None,
+    ));
+    let mut enemy = Entity::new(
+        Vec2 { x: 7.5, y: 8.5 },
+        0.3,
+        Some(Sprite {
+            position: Vec2 { x: 7.5, y: 8.5 },
+            texture: textures[9].clone(),
+            scale_factor: Vec2 { x: 1.0, y: 1.0 },
+            vertical_offset: 0.0,
+            distance_from_camera: 0.0,
+        }),
+        Some(Box::new(Enemy::new(vec![
+            Vec2 { x: 7.5, y: 8.5 },
+            Vec2 { x: 9.5, y: 8.5 },
+            Vec2 { x: 9.5, y: 13.5 },
+            Vec2 { x: 7.5, y: 13.5 },
+        ]))),
+    );
+    enemy.health = Some(30.0);
+    entities.spawn(enemy);
+    entities.spawn(Entity::new(
+        Vec2 { x: 8.5, y: 11.5 },
+        0.2,
+        Some(Sprite {
+            position: Vec2 { x: 8.5, y: 11.5 },
+            texture: textures[10].clone(),
+            scale_factor: Vec2 { x: 1.0, y: 1.0 },
+            vertical_offset: 0.0,
+            distance_from_camera: 0.0,
+        }),
+        None,
+    ));
+    entities.spawn(Entity::new(
+        Vec2 { x: 13.5, y: 9.5 },
+        0.2,
+        Some(Sprite {
+            position: Vec2 { x: 13.5, y: 9.5 },
+            texture: Rc::new(medkit_texture()),
+            scale_factor: Vec2 { x: 0.4, y: 0.4 },
+            // sits on the floor rather than floating at eye level
+            vertical_offset: 0.3,
             distance_from_camera: 0.0,
+        }),
+        Some(Box::new(Pickup::new(25.0))),
+    ));
+    let mut lights = LightMap::new(&world, 8);
+    lights.lights.push(PointLight {
+        position: Vec2 { x: 8.5, y: 11.5 },
+        color: 0x0060FF60,
+        radius: 4.0,
+        intensity: 1.0,
+        flicker: 0.0,
+    });
+    lights.lights.push(PointLight {
+        position: Vec2 { x: 2.5, y: 15.5 },
+        color: 0x00FFA040,
+        radius: 3.5,
+        intensity: 1.2,
+        flicker: 0.4,
+    });
+    let mut health = 100.0;
+    let mut tints = Tints::new();
+    // each toggled by the number keys, in this order
+    let mut post_process = PostProcess::new(vec![
+        Effect::Dither { levels: 8 },
+        Effect::Scanlines { darkness: 0.35 },
+        Effect::Curvature { amount: 0.08 },
+        Effect::Gamma { gamma: 1.3 },
+        Effect::Quantise { levels: 12 },
+        Effect::Vignette { strength: 0.6 },
+        Effect::Bloom { threshold: 170, radius: 12, strength: 1.0 },
+    ]);
+    let post_process_keys = [Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5, Key::Key6, Key::Key7];
+    let mut weapon = Weapon::pistol();
+    let mut decals = Decals::new(16);
+    let mut particles = Particles::new();
+    particles.add_emitter(Emitter {
+        rate: 12.0,
+        speed: 0.05,
+        vertical_speed: 0.05,
+        gravity: -0.15,
+        lifetime: 3.0,
+        start_color: 0x00606060,
+        end_color: 0x00202020,
+        start_size: 0.04,
+        end_size: 0.15,
+        ..Emitter::new(Vec2 { x: 3.0, y: 8.0 }, 0.6)
+    });
+    decals.add(
+        Vec2 { x: 0, y: 12 },
+        Face::XPositive,
+        Decal {
+            texture: textures[0].clone(),
+            min: Vec2 { x: 0.3, y: 0.25 },
+            max: Vec2 { x: 0.7, y: 0.65 },
+            lifetime: None,
         },
-    ];
+    );
 
     let mut window = Window::new(
         "Raycasting Demo",
-        framebuffer.width,
-        framebuffer.height,
-        WindowOptions::default(),
+        WINDOW_WIDTH,
+        WINDOW_HEIGHT,
+        WindowOptions {
+            resize: true,
+            ..WindowOptions::default()
+        },
     )
     .unwrap();
     window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
 
     while window.is_open() {
-        framebuffer.draw_floor_and_ceiling(&camera, &world);
-        for x in 0..framebuffer.width {
-            let mut side_dist = Vec2::<f64>::new();
-            let mut ray = camera.get_ray(x, framebuffer.width);
-            let mut map = camera.position.as_usize();
-            let delta_dist = Vec2 {
-                x: (1.0 / ray.direction.x).abs(),
-                y: (1.0 / ray.direction.y).abs(),
-            };
-            let mut side: Side;
-            let step = Vec2 {
-                x: Step::from(ray.direction.x < 0.0),
-                y: Step::from(ray.direction.y < 0.0),
-            };
-            if ray.direction.x < 0.0 {
-                side_dist.x = (camera.position.x - (map.x as f64)) * delta_dist.x;
-            } else {
-                side_dist.x = ((map.x as f64) + 1.0 - camera.position.x) * delta_dist.x;
-            }
-            if ray.direction.y < 0.0 {
-                side_dist.y = (camera.position.y - (map.y as f64)) * delta_dist.y;
-            } else {
-                side_dist.y = ((map.y as f64) + 1.0 - camera.position.y) * delta_dist.y;
-            }
-
-            loop {
-                if side_dist.x < side_dist.y {
-                    side_dist.x += delta_dist.x;
-                    match step.x {
-                        Step::Left => map.x -= 1,
-                        Step::Right => map.x += 1,
-                    }
-                    side = Side::X;
-                } else {
-                    side_dist.y += delta_dist.y;
-                    match step.y {
-                        Step::Left => map.y -= 1,
-                        Step::Right => map.y += 1,
-                    }
-                    side = Side::Y;
-                }
-                match world.at(&map) {
-                    Some(MapCell::Wall { texture }) => {
-                        ray.intersections.push(Intersection {
-                            side: side.clone(),
-                            step: step.clone(),
-                            map_coordinates: map.clone(),
-                            wall_offset: Vec2 { x: 0.0, y: 0.0 },
-                        });
-                        if !texture.has_transparency {
-                            break;
-                        }
-                    }
-                    Some(MapCell::ThinWall {
-                        texture,
-                        orientation,
-                        offset_into_cell,
-                        ceiling_texture: _,
-                        floor_texture: _,
-                    }) => match orientation {
-                        Orientation::XAxis => {
-                            if side_dist.x - (delta_dist.x / (1.0 / offset_into_cell)) > side_dist.y
-                            {
-                                continue;
-                            } else {
-                                ray.intersections.push(Intersection {
-                                    side: Side::X,
-                                    step: step.clone(),
-                                    map_coordinates: map.clone(),
-                                    wall_offset: Vec2 {
-                                        x: offset_into_cell * step.x.value() as f64,
-                                        y: 0.0,
-                                    },
-                                });
-                                if !texture.has_transparency {
-                                    break;
-                                }
-                            }
-                        }
-                        Orientation::YAxis => {
-                            if side_dist.y - (delta_dist.y / (1.0 / offset_into_cell)) > side_dist.x
-                            {
-                                continue;
-                            } else {
-                                ray.intersections.push(Intersection {
-                                    side: Side::Y,
-                                    step: step.clone(),
-                                    map_coordinates: map.clone(),
-                                    wall_offset: Vec2 {
-                                        x: 0.0,
-                                        y: offset_into_cell * step.y.value() as f64,
-                                    },
-                                });
-                                if !texture.has_transparency {
-                                    break;
-                                }
-                            }
-                        }
-                    },
-                    Some(MapCell::Empty {
-                        ceiling_texture: _,
-                        floor_texture: _,
-                        fog: _,
-                        fog_color: _,
-                    }) => continue,
-                    None => break,
-                }
-            }
+        let render_start = Instant::now();
+        let (window_width, window_height) = window.get_size();
+        // a minimised window has nothing to draw into, so just keep it responding
+        // and leave the time spent minimised out of the next frame
+        if window_width == 0 || window_height == 0 {
+            window.update();
+            time = Instant::now();
+            continue;
+        }
+        let new_viewport = aspect.viewport(window_width, window_height);
+        if (window_width, window_height) != window_size || new_viewport != viewport {
+            window_size = (window_width, window_height);
+            window_pixels = vec![0; window_width * window_height];
+            viewport = new_viewport;
+            let (width, height) = resolution.size(&viewport);
+            framebuffer.resize(height, width);
+            camera.set_aspect(viewport.width as f64 / viewport.height as f64);
+        }
+        // cast every column first, since floors, sprites and particles need the fog along each ray
+        let rays: Vec<Ray> = (0..framebuffer.width)
+            .map(|x| {
+                let mut ray = camera.get_ray(x, framebuffer.width);
+                ray.cast(&camera.position, &world, f64::INFINITY);
+                ray
+            })
+            .collect();
+        framebuffer.draw_floor_and_ceiling(&camera, &world, &lights, &rays);
+        for (x, ray) in rays.iter().enumerate() {
             for intersection in &ray.intersections {
-                let perp_wall_dist = match &intersection.side {
-                    Side::X => {
-                        ((intersection.map_coordinates.x as f64) - camera.position.x
-                            + intersection.wall_offset.x
-                            + ((1 - intersection.step.x.value()) as f64) / 2.0)
-                            / ray.direction.x
-                    }
-                    Side::Y => {
-                        ((intersection.map_coordinates.y as f64) - camera.position.y
-                            + intersection.wall_offset.y
-                            + ((1 - intersection.step.y.value()) as f64) / 2.0)
-                            / ray.direction.y
-                    }
-                };
-                if let Some(cell) = world.at(&intersection.map_coordinates) {
-                    framebuffer.draw_wall(
-                        &camera,
-                        x,
-                        perp_wall_dist,
-                        cell,
-                        &intersection.side,
-                        &ray,
-                        &world,
-                    );
-                }
+                let perp_wall_dist = intersection.distance(&camera.position, &ray.direction);
+                framebuffer.draw_wall(
+                    &camera,
+                    x,
+                    perp_wall_dist,
+                    intersection,
+                    ray,
+                    &world,
+                    &decals,
+                    &lights,
+                );
+            }
+        }
+        for id in entities.near(&camera.position, SPRITE_DRAW_DISTANCE) {
+            if let Some(sprite) = entities.get_mut(id).and_then(|entity| entity.sprite.as_mut()) {
+                framebuffer.draw_sprite(&camera, sprite, &world, &lights, &rays);
             }
         }
-        framebuffer.draw_sprites(&camera, &mut sprites, &world);
+        framebuffer.draw_particles(&camera, &particles, &rays);
+        weapon.draw(&mut framebuffer);
         old_time = time;
         time = Instant::now();
         let frame_time = (time - old_time).as_secs_f64();
         framebuffer.write_ascii_string(
             0,
             0,
-            &format!("{:.3}", (1.0 / frame_time)).into_bytes(),
+            &format!("{:.3} ({}x{})", (1.0 / frame_time), framebuffer.width, framebuffer.height).into_bytes(),
             &font,
             0x00FFFFFF,
         );
@@ -252,9 +300,161 @@ fn main() {
             &font,
             0x00FFFFFF,
         );
+        framebuffer.write_ascii_string(
+            0,
+            font.glyph_size * 2,
+            &format!("health: {:.0}", health).into_bytes(),
+            &font,
+            0x00FFFFFF,
+        );
+        framebuffer.write_ascii_string(
+            0,
+            font.glyph_size * 3,
+            &format!("ammo: {}/{}", weapon.ammo, weapon.magazine_size).into_bytes(),
+            &font,
+            0x00FFFFFF,
+        );
+        framebuffer.present();
         camera.update_position_with_keys(frame_time, &window, &world);
+        if window.is_key_pressed(Key::P, KeyRepeat::No) {
+            framebuffer.palette = match framebuffer.palette {
+                Some(_) => None,
+                None => Some(palette.clone()),
+            };
+        }
+        for (index, key) in post_process_keys.iter().enumerate() {
+            if window.is_key_pressed(*key, KeyRepeat::No) {
+                post_process.toggle(index);
+            }
+        }
+        if window.is_key_pressed(Key::M, KeyRepeat::No) {
+            framebuffer.mipmapping = !framebuffer.mipmapping;
+        }
+        if window.is_key_pressed(Key::B, KeyRepeat::No) {
+            framebuffer.filter = match framebuffer.filter {
+                Filter::Nearest => Filter::Bilinear,
+                Filter::Bilinear => Filter::Nearest,
+            };
+        }
+        if window.is_key_pressed(Key::X, KeyRepeat::No) {
+            framebuffer.fixed_point = !framebuffer.fixed_point;
+        }
+        if window.is_key_pressed(Key::C, KeyRepeat::No) {
+            framebuffer.layout = match framebuffer.layout {
+                Layout::RowMajor => Layout::ColumnMajor,
+                Layout::ColumnMajor => Layout::RowMajor,
+            };
+        }
+        if window.is_key_pressed(Key::U, KeyRepeat::No) {
+            upscale = match upscale {
+                Upscale::Nearest => Upscale::Integer,
+                Upscale::Integer => Upscale::Nearest,
+            };
+        }
+        if window.is_key_pressed(Key::L, KeyRepeat::No) {
+            aspect = match aspect {
+                Aspect::HorPlus => Aspect::Letterbox(WINDOW_WIDTH as f64 / WINDOW_HEIGHT as f64),
+                Aspect::Letterbox(_) => Aspect::HorPlus,
+            };
+        }
+        if window.is_key_pressed(Key::LeftBracket, KeyRepeat::Yes) {
+            camera.set_fov(camera.fov - 5.0);
+        }
+        if window.is_key_pressed(Key::RightBracket, KeyRepeat::Yes) {
+            camera.set_fov(camera.fov + 5.0);
+        }
+        if window.is_key_pressed(Key::F, KeyRepeat::No) {
+            resolution.enabled = !resolution.enabled;
+        }
+        if window.is_key_down(Key::R) {
+            weapon.reload();
+        }
+        if window.is_key_down(Key::Space) && weapon.trigger() {
+            // where the crosshair sits on a wall's texture, whatever the distance
+            let impact_v = 0.5 - camera.height;
+            match hitscan(&camera, &world, &entities, weapon.range) {
+                Some(Target::Entity(id)) => {
+                    if let Some(entity) = entities.get(id) {
+                        particles.burst(
+                            &Emitter {
+                                speed: 0.8,
+                                vertical_speed: 0.8,
+                                gravity: 3.0,
+                                lifetime: 0.5,
+                                start_color: 0x00B00000,
+                                end_color: 0x00500000,
+                                start_size: 0.03,
+                                end_size: 0.02,
+                                ..Emitter::new(entity.position.clone(), 1.0 - impact_v)
+                            },
+                            12,
+                        );
+                    }
+                    entities.damage(id, weapon.damage, &camera.position);
+                }
+                Some(Target::Wall(hit)) => {
+                    particles.burst(
+                        &Emitter {
+                            speed: 1.2,
+                            vertical_speed: 1.0,
+                            gravity: 4.0,
+                            lifetime: 0.4,
+                            start_color: 0x00FFE080,
+                            end_color: 0x00803000,
+                            start_size: 0.02,
+                            end_size: 0.01,
+                            ..Emitter::new(&hit.point - &camera.direction * 0.05, 1.0 - impact_v)
+                        },
+                        10,
+                    );
+                    decals.add(
+                        hit.cell,
+                        Face::new(&hit.side, &camera.direction),
+                        Decal {
+                            texture: weapon.impact_texture.clone(),
+                            min: Vec2 {
+                                x: hit.texture_x - 0.04,
+                                y: impact_v - 0.04,
+                            },
+                            max: Vec2 {
+                                x: hit.texture_x + 0.04,
+                                y: impact_v + 0.04,
+                            },
+                            lifetime: Some(30.0),
+                        },
+                    );
+                }
+                None => {}
+            }
+        }
+        weapon.update(frame_time);
+        decals.update(frame_time);
+        particles.update(frame_time, &world);
+        lights.update(frame_time, &world);
+        for event in entities.update(frame_time, &world, &camera) {
+            match event {
+                Event::Attack { damage } => {
+                    health = (health - damage).max(0.0);
+                    tints.flash(tint::DAMAGE, damage / 25.0, 0.6);
+                }
+                Event::Pickup { id, health: amount } => {
+                    health = (health + amount).min(100.0);
+                    entities.despawn(id);
+                    tints.flash(tint::PICKUP, 0.4, 0.5);
+                }
+            }
+        }
+        tints.update(frame_time);
+        tints.underwater = world.is_water(&camera.position.as_usize());
+        tints.apply(&mut framebuffer.pixels);
+        post_process.apply(&mut framebuffer);
+        framebuffer.upscale(&mut window_pixels, window_size.0, &viewport, &upscale);
+        if resolution.update(render_start.elapsed().as_secs_f64(), frame_time) {
+            let (width, height) = resolution.size(&viewport);
+            framebuffer.resize(height, width);
+        }
         window
-            .update_with_buffer(&framebuffer.pixels, framebuffer.width, framebuffer.height)
+            .update_with_buffer(&window_pixels, window_size.0, window_size.1)
             .unwrap();
         framebuffer.clear_z_buffer();
     }
@@ -1,7 +1,10 @@
 use std::rc::Rc;
 
 use crate::vec2::Vec2;
-use crate::util::Orientation;
+use crate::camera::Ray;
+use crate::fog::Fog;
+use crate::pathfinding;
+use crate::util::{Orientation, Side};
 use crate::texture::Texture;
 
 pub enum MapCell {
@@ -10,9 +13,15 @@ pub enum MapCell {
         floor_texture: Rc<Texture>,
         fog: f64,
         fog_color: u32,
+        // fog only lies below this height, thinning out towards it from the floor; None fills the whole cell
+        fog_height: Option<f64>,
+        // sector style brightness from 0 to 255, dimmed with distance by the light map's LightLevels
+        light_level: u8,
+        water: bool,
     },
     Wall {
         texture: Rc<Texture>,
+        light_level: u8,
     },
     ThinWall {
         texture: Rc<Texture>,
@@ -20,9 +29,44 @@ pub enum MapCell {
         offset_into_cell: f64,
         ceiling_texture: Rc<Texture>,
         floor_texture: Rc<Texture>,
+        fog: f64,
+        fog_color: u32,
+        fog_height: Option<f64>,
+        light_level: u8,
     },
 }
 
+impl MapCell {
+    // density per world unit, colour and layer height, for cells the camera can see into
+    pub fn fog(&self) -> Option<(f64, u32, Option<f64>)> {
+        match self {
+            MapCell::Empty { fog, fog_color, fog_height, .. } | MapCell::ThinWall { fog, fog_color, fog_height, .. } => Some((*fog, *fog_color, *fog_height)),
+            MapCell::Wall { .. } => None,
+        }
+    }
+
+    pub fn light_level(&self) -> u8 {
+        match self {
+            MapCell::Empty { light_level, .. } | MapCell::Wall { light_level, .. } | MapCell::ThinWall { light_level, .. } => *light_level,
+        }
+    }
+
+    pub fn set_light_level(&mut self, level: u8) {
+        match self {
+            MapCell::Empty { light_level, .. } | MapCell::Wall { light_level, .. } | MapCell::ThinWall { light_level, .. } => *light_level = level,
+        }
+    }
+}
+
+pub struct Hit {
+    pub cell: Vec2<usize>,
+    pub side: Side,
+    pub point: Vec2<f64>,
+    pub distance: f64,
+    // across the face from 0 to 1, matching the column draw_wall samples
+    pub texture_x: f64,
+}
+
 pub struct Map {
     width: usize,
     height: usize,
@@ -35,14 +79,44 @@ impl Map {
         let mut cells = Vec::with_capacity(layout.len());
         for cell in layout.split(',') {
             match cell {
-                "0" => cells.push(MapCell::Empty { ceiling_texture: texture_atlas[6].clone(), floor_texture: texture_atlas[3].clone(), fog: 0.08, fog_color: 0x00000000 }),
-                _ => cells.push(MapCell::Wall { texture: texture_atlas[cell.parse::<usize>().unwrap() - 1].clone() }),
+                "0" => cells.push(MapCell::Empty { ceiling_texture: texture_atlas[6].clone(), floor_texture: texture_atlas[3].clone(), fog: 0.08, fog_color: 0x00000000, fog_height: None, light_level: 255, water: false }),
+                _ => cells.push(MapCell::Wall { texture: texture_atlas[cell.parse::<usize>().unwrap() - 1].clone(), light_level: 255 }),
+            }
+        }
+        cells[5 * 24 + 9] = MapCell::ThinWall { texture: texture_atlas[6].clone(), orientation: Orientation::XAxis, offset_into_cell: 0.5, ceiling_texture: texture_atlas[6].clone(), floor_texture: texture_atlas[3].clone(), fog: 0.08, fog_color: 0x00000000, fog_height: None, light_level: 255 };
+        cells[5 * 24 + 10] = MapCell::ThinWall { texture: texture_atlas[5].clone(), orientation: Orientation::XAxis, offset_into_cell: 0.5, ceiling_texture: texture_atlas[6].clone(), floor_texture: texture_atlas[3].clone(), fog: 0.08, fog_color: 0x00000000, fog_height: None, light_level: 255 };
+        cells[5 * 24 + 11] = MapCell::ThinWall { texture: texture_atlas[4].clone(), orientation: Orientation::XAxis, offset_into_cell: 0.5, ceiling_texture: texture_atlas[6].clone(), floor_texture: texture_atlas[3].clone(), fog: 0.08, fog_color: 0x00000000, fog_height: None, light_level: 255 };
+        cells[3 * 24 + 2] = MapCell::ThinWall { texture: texture_atlas[9].clone(), orientation: Orientation::YAxis, offset_into_cell: 0.5, ceiling_texture: texture_atlas[6].clone(), floor_texture: texture_atlas[3].clone(), fog: 0.08, fog_color: 0x00000000, fog_height: None, light_level: 255 };
+        // the pillared room is kept dim, with its walls a little brighter than the floor, and mist lying over the floor
+        for x in 6..11 {
+            for y in 7..16 {
+                let cell = &mut cells[x * 24 + y];
+                let level = if let MapCell::Wall { .. } = cell { 144 } else { 112 };
+                cell.set_light_level(level);
+                if let MapCell::Empty { fog, fog_color, fog_height, .. } = cell {
+                    *fog = 1.5;
+                    *fog_color = 0x00707A80;
+                    *fog_height = Some(0.35);
+                }
+            }
+        }
+        // a bank of thick green fog filling the room in the corner
+        for x in 1..5 {
+            for y in 17..23 {
+                if let MapCell::Empty { fog, fog_color, .. } = &mut cells[x * 24 + y] {
+                    *fog = 0.6;
+                    *fog_color = 0x00304A30;
+                }
+            }
+        }
+        // the west end of the long corridor is flooded
+        for y in 1..7 {
+            if let MapCell::Empty { fog, fog_color, water, .. } = &mut cells[13 * 24 + y] {
+                *fog = 0.3;
+                *fog_color = 0x00205860;
+                *water = true;
             }
         }
-        cells[5 * 24 + 9] = MapCell::ThinWall { texture: texture_atlas[6].clone(), orientation: Orientation::XAxis, offset_into_cell: 0.5, ceiling_texture: texture_atlas[6].clone(), floor_texture: texture_atlas[3].clone() };
-        cells[5 * 24 + 10] = MapCell::ThinWall { texture: texture_atlas[5].clone(), orientation: Orientation::XAxis, offset_into_cell: 0.5, ceiling_texture: texture_atlas[6].clone(), floor_texture: texture_atlas[3].clone() };
-        cells[5 * 24 + 11] = MapCell::ThinWall { texture: texture_atlas[4].clone(), orientation: Orientation::XAxis, offset_into_cell: 0.5, ceiling_texture: texture_atlas[6].clone(), floor_texture: texture_atlas[3].clone() };
-        cells[3 * 24 + 2] = MapCell::ThinWall { texture: texture_atlas[9].clone(), orientation: Orientation::YAxis, offset_into_cell: 0.5, ceiling_texture: texture_atlas[6].clone(), floor_texture: texture_atlas[3].clone() };
         Map {
             width: 24,
             height: 24,
@@ -51,10 +125,198 @@ impl Map {
     }
 
     pub fn at(&self, position: &Vec2<usize>) -> Option<&MapCell> {
+        self.cells.get(self.index(position)?)
+    }
+
+    pub fn index(&self, position: &Vec2<usize>) -> Option<usize> {
         if position.x < self.height && position.y < self.width {
-            self.cells.get(position.x * self.width + position.y)
+            Some(position.x * self.width + position.y)
         } else {
             None
         }
     }
+
+    // cells along each axis; x indexes rows, so it runs up to height
+    pub fn size(&self) -> Vec2<usize> {
+        Vec2 {
+            x: self.height,
+            y: self.width,
+        }
+    }
+
+    pub fn is_water(&self, position: &Vec2<usize>) -> bool {
+        matches!(self.at(position), Some(MapCell::Empty { water: true, .. }))
+    }
+
+    pub fn fog_colors(&self) -> Vec<u32> {
+        let mut colors = Vec::new();
+        for (_, fog_color, _) in self.cells.iter().filter_map(|cell| cell.fog()) {
+            if !colors.contains(&fog_color) {
+                colors.push(fog_color);
+            }
+        }
+        colors
+    }
+
+    pub fn cell_count(&self) -> usize {
+        self.cells.len()
+    }
+
+    // whether something can move through this cell along the given axis; thin walls only block movement across them
+    pub fn is_passable(&self, position: &Vec2<usize>, side: &Side) -> bool {
+        match self.at(position) {
+            Some(MapCell::Empty { ceiling_texture: _, floor_texture: _, fog: _, fog_color: _, fog_height: _, light_level: _, water: _ }) => true,
+            Some(MapCell::ThinWall { texture: _, orientation, offset_into_cell: _, ceiling_texture: _, floor_texture: _, fog: _, fog_color: _, fog_height: _, light_level: _ }) => {
+                !matches!((orientation, side), (Orientation::XAxis, Side::X) | (Orientation::YAxis, Side::Y))
+            },
+            Some(MapCell::Wall { texture: _, light_level: _ }) | None => false,
+        }
+    }
+
+    // the first wall face along the ray, transparent or not; a zero direction hits nothing
+    pub fn cast_ray(&self, origin: &Vec2<f64>, direction: &Vec2<f64>, max_distance: f64) -> Option<Hit> {
+        if direction.length_squared() < f64::EPSILON {
+            return None;
+        }
+        let mut ray = Ray {
+            direction: direction / direction.length(),
+            intersections: Vec::new(),
+            fog: Fog::new(),
+        };
+        ray.cast(origin, self, max_distance);
+        let intersection = ray.intersections.into_iter().next()?;
+        let distance = intersection.distance(origin, &ray.direction);
+        if distance > max_distance {
+            return None;
+        }
+        let point = origin + &ray.direction * distance;
+        let wall_x = match intersection.side {
+            Side::X => point.y,
+            Side::Y => point.x,
+        }.fract();
+        let flipped = match intersection.side {
+            Side::X => ray.direction.x > 0.0,
+            Side::Y => ray.direction.y < 0.0,
+        };
+        Some(Hit {
+            cell: intersection.map_coordinates,
+            side: intersection.side,
+            point,
+            distance,
+            texture_x: if flipped { 1.0 - wall_x } else { wall_x },
+        })
+    }
+
+    // whether nothing between two points blocks the line joining them
+    // walls with transparent texels only let it through when see_through_transparent is set
+    pub fn line_of_sight(&self, from: &Vec2<f64>, to: &Vec2<f64>, see_through_transparent: bool) -> bool {
+        let mut ray = Ray {
+            direction: to - from,
+            intersections: Vec::new(),
+            fog: Fog::new(),
+        };
+        if ray.direction.length_squared() < f64::EPSILON {
+            return true;
+        }
+        ray.cast(from, self, 1.0);
+        ray.intersections.iter().all(|intersection| {
+            intersection.distance(from, &ray.direction) >= 1.0
+                || match self.at(&intersection.map_coordinates) {
+                    Some(MapCell::Wall { texture, light_level: _ }) | Some(MapCell::ThinWall { texture, .. }) => {
+                        see_through_transparent && texture.has_transparency
+                    }
+                    _ => true,
+                }
+        })
+    }
+
+    pub fn find_path(&self, from: &Vec2<usize>, to: &Vec2<usize>) -> Option<Vec<Vec2<usize>>> {
+        pathfinding::find_path(self, from, to)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn texture(has_transparency: bool) -> Rc<Texture> {
+        Rc::new(Texture::new(1, 1, vec![if has_transparency { 0 } else { 0x00808080 }]))
+    }
+
+    // one string per row, so a character's row is its x and its column its y
+    // '#' is a wall, 'o' a wall with transparent texels, '-' and '|' thin walls across x and y, anything else is empty
+    pub fn map(rows: &[&str]) -> Map {
+        map_with_walls(rows, texture(false))
+    }
+
+    // the same, with every '#' wall drawn with this texture
+    pub fn map_with_walls(rows: &[&str], wall: Rc<Texture>) -> Map {
+        let mut cells = Vec::new();
+        for row in rows {
+            for cell in row.chars() {
+                let thin_wall = |orientation| MapCell::ThinWall { texture: texture(false), orientation, offset_into_cell: 0.5, ceiling_texture: texture(false), floor_texture: texture(false), fog: 0.0, fog_color: 0, fog_height: None, light_level: 255 };
+                cells.push(match cell {
+                    '#' => MapCell::Wall { texture: wall.clone(), light_level: 255 },
+                    'o' => MapCell::Wall { texture: texture(true), light_level: 255 },
+                    '-' => thin_wall(Orientation::XAxis),
+                    '|' => thin_wall(Orientation::YAxis),
+                    _ => MapCell::Empty { ceiling_texture: texture(false), floor_texture: texture(false), fog: 0.0, fog_color: 0, fog_height: None, light_level: 255, water: false },
+                });
+            }
+        }
+        Map {
+            width: rows[0].len(),
+            height: rows.len(),
+            cells,
+        }
+    }
+
+    const ROOM: [&str; 5] = ["#####", "#...#", "#...#", "#...#", "#####"];
+
+    #[test]
+    fn cast_ray_hits_the_first_wall_face() {
+        let world = map(&ROOM);
+        // the direction's length doesn't matter, distances are in world units
+        let hit = world.cast_ray(&Vec2 { x: 2.25, y: 2.25 }, &Vec2 { x: 0.0, y: 2.0 }, 10.0).unwrap();
+        assert!(hit.cell == Vec2 { x: 2, y: 4 });
+        assert!(matches!(hit.side, Side::Y));
+        assert!((hit.distance - 1.75).abs() < 1e-9);
+        assert!((hit.texture_x - 0.25).abs() < 1e-9);
+        // texture_x runs the other way on faces seen from the other side, as draw_wall does
+        let hit = world.cast_ray(&Vec2 { x: 2.25, y: 2.25 }, &Vec2 { x: 0.0, y: -1.0 }, 10.0).unwrap();
+        assert!(hit.cell == Vec2 { x: 2, y: 0 });
+        assert!((hit.distance - 1.25).abs() < 1e-9);
+        assert!((hit.texture_x - 0.75).abs() < 1e-9);
+        let hit = world.cast_ray(&Vec2 { x: 2.25, y: 2.25 }, &Vec2 { x: 1.0, y: 0.0 }, 10.0).unwrap();
+        assert!(hit.cell == Vec2 { x: 4, y: 2 });
+        assert!(matches!(hit.side, Side::X));
+        assert!((hit.distance - 1.75).abs() < 1e-9);
+    }
+
+    #[test]
+    fn cast_ray_stops_at_max_distance() {
+        let world = map(&ROOM);
+        assert!(world.cast_ray(&Vec2 { x: 2.25, y: 2.25 }, &Vec2 { x: 0.0, y: 1.0 }, 1.5).is_none());
+        assert!(world.cast_ray(&Vec2 { x: 2.25, y: 2.25 }, &Vec2 { x: 0.0, y: 1.0 }, 1.8).is_some());
+    }
+
+    #[test]
+    fn cast_ray_with_no_direction_hits_nothing() {
+        let world = map(&ROOM);
+        assert!(world.cast_ray(&Vec2 { x: 2.25, y: 2.25 }, &Vec2 { x: 0.0, y: 0.0 }, 10.0).is_none());
+    }
+
+    #[test]
+    fn line_of_sight_stops_at_walls() {
+        let world = map(&["#####", "#...#", "#.#.#", "#...#", "#####"]);
+        assert!(world.line_of_sight(&Vec2 { x: 1.5, y: 1.5 }, &Vec2 { x: 1.5, y: 3.5 }, false));
+        assert!(!world.line_of_sight(&Vec2 { x: 2.5, y: 1.5 }, &Vec2 { x: 2.5, y: 3.5 }, true));
+    }
+
+    #[test]
+    fn only_sight_gets_through_transparent_walls() {
+        let world = map(&["#####", "#...#", "#.o.#", "#...#", "#####"]);
+        assert!(world.line_of_sight(&Vec2 { x: 2.5, y: 1.5 }, &Vec2 { x: 2.5, y: 3.5 }, true));
+        assert!(!world.line_of_sight(&Vec2 { x: 2.5, y: 1.5 }, &Vec2 { x: 2.5, y: 3.5 }, false));
+    }
 }
@@ -0,0 +1,197 @@
+use crate::light::Light;
+use crate::texture::{Filter, Texture, Wrap};
+
+// colormaps run from black up to twice as bright, so the middle one leaves colours as they are
+const COLORMAPS: usize = 32;
+const FOG_STEPS: usize = 32;
+
+// 256 colours with Doom style COLORMAP tables, so shading and fog are lookups rather than per channel maths
+pub struct Palette {
+    // index 0 is black, and only black maps to it, so it stays transparent
+    pub colors: Vec<u32>,
+    // the nearest index for each colour with 5 bits per channel
+    nearest: Vec<u8>,
+    colormaps: Vec<[u8; 256]>,
+    // for each fog colour, FOG_STEPS tables blending further towards it
+    fog_maps: Vec<(u32, Vec<[u8; 256]>)>,
+}
+
+impl Palette {
+    // picks the colours by median cut over every texel
+    pub fn new(textures: &[Texture]) -> Palette {
+        let texels: Vec<[u8; 3]> = textures
+            .iter()
+            .flat_map(|texture| texture.data.iter())
+            .filter(|&&color| (color & 0x00FFFFFF) != 0)
+            .map(|color| {
+                let bytes = color.to_le_bytes();
+                [bytes[0], bytes[1], bytes[2]]
+            })
+            .collect();
+        let mut colors = vec![0];
+        colors.extend(median_cut(texels, 255));
+        colors.resize(256, 0);
+
+        let mut nearest = Vec::with_capacity(1 << 15);
+        for quantized in 0..(1 << 15) {
+            let color = [
+                ((quantized & 0x1F) << 3 | 0x04) as u8,
+                ((quantized >> 5 & 0x1F) << 3 | 0x04) as u8,
+                ((quantized >> 10 & 0x1F) << 3 | 0x04) as u8,
+            ];
+            nearest.push(search(&colors, color));
+        }
+        let mut palette = Palette {
+            colors,
+            nearest,
+            colormaps: Vec::new(),
+            fog_maps: Vec::new(),
+        };
+        palette.colormaps = (0..COLORMAPS)
+            .map(|level| palette.table(|color| scale(color, level as f64 / (COLORMAPS / 2) as f64)))
+            .collect();
+        palette
+    }
+
+    pub fn add_fog_color(&mut self, fog_color: u32) {
+        if self.fog_maps.iter().any(|(color, _)| *color == fog_color) {
+            return;
+        }
+        let maps = (0..FOG_STEPS)
+            .map(|step| self.table(|color| blend(color, fog_color, step as f64 / (FOG_STEPS - 1) as f64)))
+            .collect();
+        self.fog_maps.push((fog_color, maps));
+    }
+
+    fn table(&self, f: impl Fn(u32) -> u32) -> [u8; 256] {
+        let mut table = [0; 256];
+        for (index, entry) in table.iter_mut().enumerate() {
+            *entry = self.nearest(f(self.colors[index]));
+        }
+        table
+    }
+
+    pub fn index(&self, texture: &mut Texture) {
+        texture.indices = texture.data.iter().map(|&color| self.nearest(color)).collect();
+        for mipmap in &mut texture.mipmaps {
+            self.index(mipmap);
+        }
+    }
+
+    pub fn nearest(&self, color: u32) -> u8 {
+        if (color & 0x00FFFFFF) == 0 {
+            return 0;
+        }
+        let bytes = color.to_le_bytes();
+        self.nearest[(bytes[0] as usize >> 3) | (bytes[1] as usize >> 3) << 5 | (bytes[2] as usize >> 3) << 10]
+    }
+
+    // x and y are in texels, in 16.16 fixed point, as for Texture::offset_fixed
+    // falls back to matching the colour for textures that were never indexed
+    pub fn texel(&self, texture: &Texture, x: i64, y: i64, wrap: Wrap) -> u8 {
+        match texture.indices.get(texture.offset_fixed(x, y, wrap)) {
+            Some(&index) => index,
+            None => self.nearest(texture.filtered_fixed(x, y, wrap, Filter::Nearest)),
+        }
+    }
+
+    // coloured light can't be kept in a palette, so only its overall brightness counts
+    pub fn shade(&self, index: u8, light: &Light) -> u8 {
+        let brightness = (light[0] as usize + light[1] as usize + light[2] as usize) / 3;
+        self.colormaps[((brightness * (COLORMAPS / 2)) >> 8).min(COLORMAPS - 1)][index as usize]
+    }
+
+    // shades and fogs a palette index, then gives back its colour
+    pub fn color(&self, index: u8, light: &Light, (amount, fog_color): (f64, u32)) -> u32 {
+        self.colors[self.fog(self.shade(index, light), fog_color, amount) as usize]
+    }
+
+    // uses the tables for whichever added fog colour is closest
+    pub fn fog(&self, index: u8, fog_color: u32, amount: f64) -> u8 {
+        let fog_bytes = fog_color.to_le_bytes();
+        let maps = self.fog_maps.iter().min_by_key(|(color, _)| {
+            let bytes = color.to_le_bytes();
+            (0..3).map(|channel| (bytes[channel] as i32 - fog_bytes[channel] as i32).pow(2)).sum::<i32>()
+        });
+        match maps {
+            Some((_, maps)) => maps[(amount * (FOG_STEPS - 1) as f64).round() as usize][index as usize],
+            None => index,
+        }
+    }
+}
+
+fn median_cut(texels: Vec<[u8; 3]>, count: usize) -> Vec<u32> {
+    let mut boxes = vec![widest_channel(texels)];
+    while boxes.len() < count {
+        // split whichever box is widest along any channel, at the median along that channel
+        let (index, channel) = match boxes.iter().enumerate().max_by_key(|(_, (_, _, range))| *range) {
+            Some((index, (_, channel, range))) if *range > 0 => (index, *channel),
+            _ => break,
+        };
+        let (mut texels, _, _) = boxes.swap_remove(index);
+        texels.sort_unstable_by_key(|texel| texel[channel]);
+        let upper = texels.split_off(texels.len() / 2);
+        boxes.push(widest_channel(texels));
+        boxes.push(widest_channel(upper));
+    }
+    boxes
+        .iter()
+        .filter(|(texels, _, _)| !texels.is_empty())
+        .map(|(texels, _, _)| {
+            let mut sum = [0; 3];
+            for texel in texels {
+                for channel in 0..3 {
+                    sum[channel] += texel[channel] as usize;
+                }
+            }
+            u32::from_le_bytes([
+                (sum[0] / texels.len()) as u8,
+                (sum[1] / texels.len()) as u8,
+                (sum[2] / texels.len()) as u8,
+                0x00,
+            ])
+        })
+        .collect()
+}
+
+fn widest_channel(texels: Vec<[u8; 3]>) -> (Vec<[u8; 3]>, usize, u8) {
+    let (channel, range) = (0..3)
+        .map(|channel| {
+            let min = texels.iter().map(|texel| texel[channel]).min().unwrap_or(0);
+            let max = texels.iter().map(|texel| texel[channel]).max().unwrap_or(0);
+            (channel, max - min)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap();
+    (texels, channel, range)
+}
+
+// skips index 0, so nothing but black becomes transparent
+fn search(colors: &[u32], color: [u8; 3]) -> u8 {
+    let mut best = (1, i32::MAX);
+    for (index, candidate) in colors.iter().enumerate().skip(1) {
+        let bytes = candidate.to_le_bytes();
+        let distance = (0..3).map(|channel| (bytes[channel] as i32 - color[channel] as i32).pow(2)).sum::<i32>();
+        if distance < best.1 {
+            best = (index, distance);
+        }
+    }
+    best.0 as u8
+}
+
+fn scale(color: u32, brightness: f64) -> u32 {
+    let mut bytes = color.to_le_bytes();
+    for byte in bytes.iter_mut().take(3) {
+        *byte = (*byte as f64 * brightness).min(255.0) as u8;
+    }
+    u32::from_le_bytes(bytes)
+}
+
+fn blend(color: u32, fog_color: u32, amount: f64) -> u32 {
+    let mut bytes = color.to_le_bytes();
+    let fog_bytes = fog_color.to_le_bytes();
+    for channel in 0..3 {
+        bytes[channel] = (fog_bytes[channel] as f64 * amount + bytes[channel] as f64 * (1.0 - amount)) as u8;
+    }
+    u32::from_le_bytes(bytes)
+}
@@ -0,0 +1,168 @@
+use std::f64::consts::PI;
+
+use crate::map::{Map, MapCell};
+use crate::vec2::Vec2;
+
+pub struct Particle {
+    pub position: Vec2<f64>,
+    // above the floor, where 1.0 is the top of a wall
+    pub height: f64,
+    pub velocity: Vec2<f64>,
+    pub vertical_velocity: f64,
+    pub gravity: f64,
+    pub age: f64,
+    pub lifetime: f64,
+    pub start_color: u32,
+    pub end_color: u32,
+    pub start_size: f64,
+    pub end_size: f64,
+}
+
+impl Particle {
+    pub fn color(&self) -> u32 {
+        let t = self.age / self.lifetime;
+        let start = self.start_color.to_le_bytes();
+        let end = self.end_color.to_le_bytes();
+        u32::from_le_bytes([
+            (start[0] as f64 + (end[0] as f64 - start[0] as f64) * t) as u8,
+            (start[1] as f64 + (end[1] as f64 - start[1] as f64) * t) as u8,
+            (start[2] as f64 + (end[2] as f64 - start[2] as f64) * t) as u8,
+            0x00,
+        ])
+    }
+
+    // in world units, like a sprite's scale factor
+    pub fn size(&self) -> f64 {
+        self.start_size + (self.end_size - self.start_size) * (self.age / self.lifetime)
+    }
+}
+
+#[derive(Clone)]
+pub struct Emitter {
+    pub position: Vec2<f64>,
+    pub height: f64,
+    // particles per second, on top of any bursts
+    pub rate: f64,
+    pub speed: f64,
+    pub vertical_speed: f64,
+    pub gravity: f64,
+    pub lifetime: f64,
+    pub start_color: u32,
+    pub end_color: u32,
+    pub start_size: f64,
+    pub end_size: f64,
+    // how long the emitter keeps going, or forever if None
+    pub duration: Option<f64>,
+}
+
+impl Emitter {
+    pub fn new(position: Vec2<f64>, height: f64) -> Emitter {
+        Emitter {
+            position,
+            height,
+            rate: 0.0,
+            speed: 1.0,
+            vertical_speed: 1.0,
+            gravity: 0.0,
+            lifetime: 1.0,
+            start_color: 0x00FFFFFF,
+            end_color: 0x00FFFFFF,
+            start_size: 0.05,
+            end_size: 0.05,
+            duration: None,
+        }
+    }
+}
+
+pub struct Particles {
+    pub particles: Vec<Particle>,
+    // along with the fraction of a particle each is part way through emitting
+    emitters: Vec<(Emitter, f64)>,
+    seed: u32,
+}
+
+impl Particles {
+    pub fn new() -> Particles {
+        Particles {
+            particles: Vec::new(),
+            emitters: Vec::new(),
+            seed: 0x2545F491,
+        }
+    }
+
+    pub fn add_emitter(&mut self, emitter: Emitter) {
+        self.emitters.push((emitter, 0.0));
+    }
+
+    pub fn burst(&mut self, emitter: &Emitter, count: usize) {
+        for _ in 0..count {
+            self.emit(emitter);
+        }
+    }
+
+    pub fn update(&mut self, delta: f64, world: &Map) {
+        let mut emitters = std::mem::take(&mut self.emitters);
+        for (emitter, pending) in &mut emitters {
+            *pending += emitter.rate * delta;
+            while *pending >= 1.0 {
+                *pending -= 1.0;
+                self.emit(emitter);
+            }
+            if let Some(duration) = &mut emitter.duration {
+                *duration -= delta;
+            }
+        }
+        emitters.retain(|(emitter, _)| !matches!(emitter.duration, Some(duration) if duration <= 0.0));
+        self.emitters = emitters;
+
+        for particle in &mut self.particles {
+            particle.age += delta;
+            particle.vertical_velocity -= particle.gravity * delta;
+            particle.height += particle.vertical_velocity * delta;
+            if particle.height <= 0.0 || particle.height >= 1.0 {
+                // comes to rest on the floor, or slides along the ceiling
+                particle.height = particle.height.clamp(0.0, 1.0);
+                particle.vertical_velocity = 0.0;
+                particle.velocity = &particle.velocity * (1.0 - (delta * 4.0).min(1.0));
+            }
+            let position = &particle.position + &particle.velocity * delta;
+            if let Some(MapCell::Wall { texture: _, light_level: _ }) | None = world.at(&position.as_usize()) {
+                particle.velocity = Vec2::new();
+            } else {
+                particle.position = position;
+            }
+        }
+        self.particles.retain(|particle| particle.age < particle.lifetime);
+    }
+
+    fn emit(&mut self, emitter: &Emitter) {
+        let angle = self.random() * 2.0 * PI;
+        let speed = emitter.speed * (0.5 + self.random() * 0.5);
+        let vertical_velocity = emitter.vertical_speed * (self.random() * 2.0 - 1.0);
+        let lifetime = emitter.lifetime * (0.75 + self.random() * 0.5);
+        self.particles.push(Particle {
+            position: emitter.position.clone(),
+            height: emitter.height,
+            velocity: Vec2 {
+                x: angle.cos() * speed,
+                y: angle.sin() * speed,
+            },
+            vertical_velocity,
+            gravity: emitter.gravity,
+            age: 0.0,
+            lifetime,
+            start_color: emitter.start_color,
+            end_color: emitter.end_color,
+            start_size: emitter.start_size,
+            end_size: emitter.end_size,
+        });
+    }
+
+    // xorshift, plenty for scattering particles
+    fn random(&mut self) -> f64 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed as f64 / u32::MAX as f64
+    }
+}
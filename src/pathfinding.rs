@@ -0,0 +1,166 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+
+use crate::map::Map;
+use crate::util::Side;
+use crate::vec2::Vec2;
+
+// 4-connected, so thin walls only need to block movement along their own axis
+fn neighbours(world: &Map, cell: &Vec2<usize>) -> Vec<Vec2<usize>> {
+    let mut neighbours = Vec::with_capacity(4);
+    let mut try_move = |next: Vec2<usize>, side: Side| {
+        if world.is_passable(cell, &side) && world.is_passable(&next, &side) {
+            neighbours.push(next);
+        }
+    };
+    if cell.x > 0 {
+        try_move(Vec2 { x: cell.x - 1, y: cell.y }, Side::X);
+    }
+    try_move(Vec2 { x: cell.x + 1, y: cell.y }, Side::X);
+    if cell.y > 0 {
+        try_move(Vec2 { x: cell.x, y: cell.y - 1 }, Side::Y);
+    }
+    try_move(Vec2 { x: cell.x, y: cell.y + 1 }, Side::Y);
+    neighbours
+}
+
+fn manhattan(a: &Vec2<usize>, b: &Vec2<usize>) -> usize {
+    (a.x as isize - b.x as isize).unsigned_abs() + (a.y as isize - b.y as isize).unsigned_abs()
+}
+
+// A*, returning every cell from `from` to `to` inclusive
+pub fn find_path(world: &Map, from: &Vec2<usize>, to: &Vec2<usize>) -> Option<Vec<Vec2<usize>>> {
+    let start = world.index(from)?;
+    let goal = world.index(to)?;
+    let mut cost = vec![usize::MAX; world.cell_count()];
+    let mut came_from: Vec<Option<Vec2<usize>>> = vec![None; world.cell_count()];
+    let mut open = BinaryHeap::new();
+    cost[start] = 0;
+    open.push(Reverse((manhattan(from, to), 0, from.x, from.y)));
+    while let Some(Reverse((_, current_cost, x, y))) = open.pop() {
+        let current = Vec2 { x, y };
+        let index = world.index(&current).unwrap();
+        if index == goal {
+            let mut path = vec![current];
+            while let Some(previous) = came_from[world.index(path.last().unwrap()).unwrap()].clone() {
+                path.push(previous);
+            }
+            path.reverse();
+            return Some(path);
+        }
+        if current_cost > cost[index] {
+            continue;
+        }
+        for next in neighbours(world, &current) {
+            let next_index = world.index(&next).unwrap();
+            if current_cost + 1 < cost[next_index] {
+                cost[next_index] = current_cost + 1;
+                came_from[next_index] = Some(current.clone());
+                open.push(Reverse((current_cost + 1 + manhattan(&next, to), current_cost + 1, next.x, next.y)));
+            }
+        }
+    }
+    None
+}
+
+// distances from every cell to a single target, so any number of agents can share one search
+pub struct FlowField {
+    pub target: Vec2<usize>,
+    distances: Vec<usize>,
+}
+
+impl FlowField {
+    pub fn new(world: &Map, target: &Vec2<usize>) -> FlowField {
+        let mut distances = vec![usize::MAX; world.cell_count()];
+        let mut queue = VecDeque::new();
+        if let Some(index) = world.index(target) {
+            distances[index] = 0;
+            queue.push_back(target.clone());
+        }
+        while let Some(current) = queue.pop_front() {
+            let distance = distances[world.index(&current).unwrap()];
+            for next in neighbours(world, &current) {
+                let next_index = world.index(&next).unwrap();
+                if distances[next_index] == usize::MAX {
+                    distances[next_index] = distance + 1;
+                    queue.push_back(next);
+                }
+            }
+        }
+        FlowField {
+            target: target.clone(),
+            distances,
+        }
+    }
+
+    pub fn distance(&self, world: &Map, cell: &Vec2<usize>) -> Option<usize> {
+        match self.distances[world.index(cell)?] {
+            usize::MAX => None,
+            distance => Some(distance),
+        }
+    }
+
+    // the neighbouring cell one step closer to the target, if the target can be reached at all
+    pub fn next(&self, world: &Map, cell: &Vec2<usize>) -> Option<Vec2<usize>> {
+        let distance = self.distance(world, cell)?;
+        if distance == 0 {
+            return None;
+        }
+        neighbours(world, cell)
+            .into_iter()
+            .find(|next| self.distance(world, next) == Some(distance - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::tests::map;
+
+    // the thin wall in the middle blocks moving along x through its cell, but not along y
+    const THIN_WALL_ROOM: [&str; 5] = ["#####", "#...#", "#.-.#", "#...#", "#####"];
+
+    fn cell(x: usize, y: usize) -> Vec2<usize> {
+        Vec2 { x, y }
+    }
+
+    #[test]
+    fn routes_around_thin_walls() {
+        let world = map(&THIN_WALL_ROOM);
+        let path = find_path(&world, &cell(1, 2), &cell(3, 2)).unwrap();
+        assert_eq!(path.len(), 5);
+        assert!(path[0] == cell(1, 2) && path[4] == cell(3, 2));
+        assert!(!path.contains(&cell(2, 2)));
+        assert!(path.windows(2).all(|step| manhattan(&step[0], &step[1]) == 1));
+    }
+
+    #[test]
+    fn passes_along_thin_walls() {
+        let world = map(&THIN_WALL_ROOM);
+        let path = find_path(&world, &cell(2, 1), &cell(2, 3)).unwrap();
+        assert!(path == vec![cell(2, 1), cell(2, 2), cell(2, 3)]);
+    }
+
+    #[test]
+    fn unreachable_goals_have_no_path() {
+        let world = map(&["#####", "#.#.#", "#####"]);
+        assert!(find_path(&world, &cell(1, 1), &cell(1, 3)).is_none());
+        assert!(FlowField::new(&world, &cell(1, 3)).next(&world, &cell(1, 1)).is_none());
+    }
+
+    #[test]
+    fn flow_field_steps_downhill_to_the_target() {
+        let world = map(&THIN_WALL_ROOM);
+        let target = cell(3, 2);
+        let field = FlowField::new(&world, &target);
+        let mut current = cell(1, 2);
+        let mut steps = 0;
+        while let Some(next) = field.next(&world, &current) {
+            assert_eq!(field.distance(&world, &next).unwrap() + 1, field.distance(&world, &current).unwrap());
+            current = next;
+            steps += 1;
+        }
+        assert!(current == target);
+        assert_eq!(steps, 4);
+    }
+}
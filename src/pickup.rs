@@ -0,0 +1,51 @@
+use crate::entity::{Behaviour, Context, Entity, Event};
+use crate::texture::Texture;
+
+// how close the player has to get, on top of the pickup's radius
+const REACH: f64 = 0.3;
+
+pub struct Pickup {
+    pub health: f64,
+    taken: bool,
+}
+
+impl Pickup {
+    pub fn new(health: f64) -> Pickup {
+        Pickup {
+            health,
+            taken: false,
+        }
+    }
+}
+
+impl Behaviour for Pickup {
+    fn update(&mut self, entity: &mut Entity, context: &mut Context) {
+        if !self.taken && (&entity.position - &context.camera.position).length() < entity.radius + REACH {
+            self.taken = true;
+            context.events.push(Event::Pickup {
+                id: context.id,
+                health: self.health,
+            });
+        }
+    }
+}
+
+// there's no pickup art in res/, so the medkit is drawn here
+pub fn medkit_texture() -> Texture {
+    let size = 64;
+    let mut data = vec![0; size * size];
+    for y in 0..size {
+        for x in 0..size {
+            data[y * size + x] = if !(8..56).contains(&x) || !(20..60).contains(&y) {
+                0
+            } else if ((28..36).contains(&x) && (28..52).contains(&y)) || ((20..44).contains(&x) && (36..44).contains(&y)) {
+                0x00D01010
+            } else if !(12..52).contains(&x) || !(24..56).contains(&y) {
+                0x00A0A0A0
+            } else {
+                0x00E8E8E8
+            };
+        }
+    }
+    Texture::new(size, size, data)
+}
@@ -0,0 +1,171 @@
+use crate::framebuffer::Framebuffer;
+
+const BAYER: [[u32; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+pub enum Effect {
+    // ordered dithering down to this many levels per channel
+    Dither { levels: u32 },
+    Scanlines { darkness: f64 },
+    // bends the picture like a CRT, 0 leaves it flat
+    Curvature { amount: f64 },
+    Gamma { gamma: f64 },
+    Quantise { levels: u32 },
+    Vignette { strength: f64 },
+    // spreads anything brighter than threshold over radius pixels
+    Bloom { threshold: u8, radius: usize, strength: f64 },
+}
+
+pub struct Pass {
+    pub effect: Effect,
+    pub enabled: bool,
+}
+
+// runs over the finished frame in order, skipping disabled passes
+pub struct PostProcess {
+    pub passes: Vec<Pass>,
+    scratch: Vec<u32>,
+}
+
+impl PostProcess {
+    pub fn new(effects: Vec<Effect>) -> PostProcess {
+        PostProcess {
+            passes: effects.into_iter().map(|effect| Pass { effect, enabled: false }).collect(),
+            scratch: Vec::new(),
+        }
+    }
+
+    pub fn toggle(&mut self, index: usize) {
+        if let Some(pass) = self.passes.get_mut(index) {
+            pass.enabled = !pass.enabled;
+        }
+    }
+
+    pub fn apply(&mut self, framebuffer: &mut Framebuffer) {
+        let width = framebuffer.width;
+        let height = framebuffer.height;
+        for pass in self.passes.iter().filter(|pass| pass.enabled) {
+            let pixels = &mut framebuffer.pixels;
+            match pass.effect {
+                Effect::Dither { levels } => {
+                    let step = 255 / (levels.max(2) - 1);
+                    for y in 0..height {
+                        for x in 0..width {
+                            let threshold = BAYER[y % 4][x % 4] * step / 16;
+                            map_channels(&mut pixels[y * width + x], |value| {
+                                ((value as u32 + threshold) / step * step).min(255) as u8
+                            });
+                        }
+                    }
+                }
+                Effect::Scanlines { darkness } => {
+                    let keep = ((1.0 - darkness) * 256.0) as u32;
+                    for row in pixels.chunks_mut(width).skip(1).step_by(2) {
+                        for pixel in row {
+                            map_channels(pixel, |value| ((value as u32 * keep) >> 8) as u8);
+                        }
+                    }
+                }
+                Effect::Curvature { amount } => {
+                    self.scratch.clone_from(pixels);
+                    for y in 0..height {
+                        for x in 0..width {
+                            let u = x as f64 / width as f64 * 2.0 - 1.0;
+                            let v = y as f64 / height as f64 * 2.0 - 1.0;
+                            let bend = 1.0 + amount * (u * u + v * v);
+                            let source_x = ((u * bend + 1.0) / 2.0 * width as f64) as i32;
+                            let source_y = ((v * bend + 1.0) / 2.0 * height as f64) as i32;
+                            pixels[y * width + x] = if source_x >= 0 && source_x < width as i32 && source_y >= 0 && source_y < height as i32 {
+                                self.scratch[source_y as usize * width + source_x as usize]
+                            } else {
+                                0
+                            };
+                        }
+                    }
+                }
+                Effect::Gamma { gamma } => {
+                    let mut table = [0; 256];
+                    for (value, entry) in table.iter_mut().enumerate() {
+                        *entry = ((value as f64 / 255.0).powf(1.0 / gamma) * 255.0).round() as u8;
+                    }
+                    for pixel in pixels.iter_mut() {
+                        map_channels(pixel, |value| table[value as usize]);
+                    }
+                }
+                Effect::Quantise { levels } => {
+                    let step = 255.0 / (levels.max(2) - 1) as f64;
+                    for pixel in pixels.iter_mut() {
+                        map_channels(pixel, |value| ((value as f64 / step).round() * step) as u8);
+                    }
+                }
+                Effect::Vignette { strength } => {
+                    for y in 0..height {
+                        let v = y as f64 / height as f64 * 2.0 - 1.0;
+                        for x in 0..width {
+                            let u = x as f64 / width as f64 * 2.0 - 1.0;
+                            let keep = (((1.0 - strength * (u * u + v * v) / 2.0).max(0.0)) * 256.0) as u32;
+                            map_channels(&mut pixels[y * width + x], |value| ((value as u32 * keep) >> 8) as u8);
+                        }
+                    }
+                }
+                Effect::Bloom { threshold, radius, strength } => {
+                    bloom(pixels, width, height, threshold, radius, strength);
+                }
+            }
+        }
+    }
+}
+
+fn map_channels(pixel: &mut u32, f: impl Fn(u8) -> u8) {
+    let mut bytes = pixel.to_le_bytes();
+    for byte in bytes.iter_mut().take(3) {
+        *byte = f(*byte);
+    }
+    *pixel = u32::from_le_bytes(bytes);
+}
+
+// the bright parts are blurred at a quarter of the resolution, which is plenty for a glow
+fn bloom(pixels: &mut [u32], width: usize, height: usize, threshold: u8, radius: usize, strength: f64) {
+    let small_width = width / 4;
+    let small_height = height / 4;
+    let mut bright = vec![[0.0; 3]; small_width * small_height];
+    for y in 0..small_height {
+        for x in 0..small_width {
+            let bytes = pixels[y * 4 * width + x * 4].to_le_bytes();
+            for channel in 0..3 {
+                bright[y * small_width + x][channel] = bytes[channel].saturating_sub(threshold) as f64;
+            }
+        }
+    }
+    let radius = (radius / 4).max(1);
+    let mut blurred = vec![[0.0; 3]; bright.len()];
+    // a box blur across then down
+    for (step, length, lines, line_step) in [(1, small_width, small_height, small_width), (small_width, small_height, small_width, 1)] {
+        for line in 0..lines {
+            for position in 0..length {
+                let mut sum = [0.0; 3];
+                let from = position.saturating_sub(radius);
+                let to = (position + radius).min(length - 1);
+                for sample in from..=to {
+                    let texel = &bright[line * line_step + sample * step];
+                    for channel in 0..3 {
+                        sum[channel] += texel[channel];
+                    }
+                }
+                for channel in 0..3 {
+                    blurred[line * line_step + position * step][channel] = sum[channel] / (to - from + 1) as f64;
+                }
+            }
+        }
+        std::mem::swap(&mut bright, &mut blurred);
+    }
+    for y in 0..small_height * 4 {
+        for x in 0..small_width * 4 {
+            let glow = &bright[(y / 4) * small_width + x / 4];
+            let mut bytes = pixels[y * width + x].to_le_bytes();
+            for channel in 0..3 {
+                bytes[channel] = (bytes[channel] as f64 + glow[channel] * strength).min(255.0) as u8;
+            }
+            pixels[y * width + x] = u32::from_le_bytes(bytes);
+        }
+    }
+}
@@ -0,0 +1,106 @@
+// how the internal framebuffer is stretched over the window
+#[derive(Clone, PartialEq)]
+pub enum Upscale {
+    // fills the window, even if pixels end up different sizes
+    Nearest,
+    // the largest whole multiple that fits, centred with black borders
+    Integer,
+}
+
+// what to do when the window isn't the shape of the view
+#[derive(Clone, PartialEq)]
+pub enum Aspect {
+    // keeps the vertical field of view, showing more or less at the sides
+    HorPlus,
+    // keeps the view at this width over height, with black bars over the rest of the window
+    Letterbox(f64),
+}
+
+impl Aspect {
+    pub fn viewport(&self, window_width: usize, window_height: usize) -> Viewport {
+        let window_width = window_width.max(1);
+        let window_height = window_height.max(1);
+        let (width, height) = match self {
+            Aspect::HorPlus => (window_width, window_height),
+            Aspect::Letterbox(ratio) => {
+                if (window_width as f64) > window_height as f64 * ratio {
+                    (((window_height as f64 * ratio) as usize).max(1), window_height)
+                } else {
+                    (window_width, ((window_width as f64 / ratio) as usize).max(1))
+                }
+            }
+        };
+        Viewport {
+            left: (window_width - width) / 2,
+            top: (window_height - height) / 2,
+            width,
+            height,
+        }
+    }
+}
+
+// the part of the window the view is drawn into
+#[derive(Clone, PartialEq)]
+pub struct Viewport {
+    pub left: usize,
+    pub top: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+// shrinks or grows the internal resolution to keep rendering within the target frame time
+pub struct DynamicResolution {
+    pub enabled: bool,
+    pub target_frame_time: f64,
+    pub min_scale: f64,
+    pub max_scale: f64,
+    // of the viewport's size along each axis
+    pub scale: f64,
+    average: f64,
+    cooldown: f64,
+}
+
+impl DynamicResolution {
+    pub fn new(scale: f64, target_frame_time: f64) -> DynamicResolution {
+        DynamicResolution {
+            enabled: false,
+            target_frame_time,
+            min_scale: 0.25,
+            max_scale: 1.0,
+            scale,
+            average: target_frame_time,
+            cooldown: 0.0,
+        }
+    }
+
+    // render_time is only the time spent drawing, not waiting on the window; returns whether the scale changed
+    pub fn update(&mut self, render_time: f64, delta: f64) -> bool {
+        self.average += (render_time - self.average) * (delta * 4.0).min(1.0);
+        self.cooldown -= delta;
+        if !self.enabled || self.cooldown > 0.0 {
+            return false;
+        }
+        // a dead band between the two thresholds stops it flickering back and forth
+        let scale = if self.average > self.target_frame_time {
+            self.scale * 0.9
+        } else if self.average < self.target_frame_time * 0.6 {
+            self.scale * 1.05
+        } else {
+            return false;
+        }
+        .clamp(self.min_scale, self.max_scale);
+        if scale == self.scale {
+            return false;
+        }
+        self.scale = scale;
+        self.cooldown = 0.5;
+        true
+    }
+
+    pub fn size(&self, viewport: &Viewport) -> (usize, usize) {
+        (
+            ((viewport.width as f64 * self.scale) as usize).max(1),
+            ((viewport.height as f64 * self.scale) as usize).max(1),
+        )
+    }
+}
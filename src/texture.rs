@@ -25,57 +25,345 @@ impl Font {
     }
 }
 
+// what sampling does with coordinates outside 0 to 1
+#[derive(Clone, Copy)]
+pub enum Wrap {
+    Repeat,
+    Clamp,
+}
+
+// 16.16 fixed point, as used for stepping texture coordinates in the renderer's inner loops
+pub fn fixed(value: f64) -> i64 {
+    (value * 65536.0) as i64
+}
+
+impl Wrap {
+    fn apply(&self, coordinate: i64, size: usize) -> usize {
+        match self {
+            Wrap::Repeat if size.is_power_of_two() => (coordinate & (size as i64 - 1)) as usize,
+            Wrap::Repeat => coordinate.rem_euclid(size as i64) as usize,
+            Wrap::Clamp => coordinate.clamp(0, size as i64 - 1) as usize,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Filter {
+    Nearest,
+    // blends the four nearest texels, in 8 bit fixed point
+    Bilinear,
+}
+
 pub struct Texture {
     pub width: usize,
     pub height: usize,
     pub has_transparency: bool,
     pub data: Vec<u32>,
+    // palette indices for the palette rendering mode, filled in by Palette::index
+    pub indices: Vec<u8>,
+    // each level half the size of the one before, down to a single texel
+    pub mipmaps: Vec<Texture>,
+    // overrides the framebuffer's filter for just this texture
+    pub filter: Option<Filter>,
 }
 
 impl Texture {
+    // black texels are transparent, as they are in BMPs
+    pub fn new(width: usize, height: usize, data: Vec<u32>) -> Texture {
+        assert!(data.len() == width * height, "texture data doesn't match its size");
+        let has_transparency = data.iter().any(|color| (color & 0x00FFFFFF) == 0);
+        let mut texture = Texture {
+            width,
+            height,
+            has_transparency,
+            data,
+            indices: Vec::new(),
+            mipmaps: Vec::new(),
+            filter: None,
+        };
+        texture.mipmaps = texture.build_mipmaps();
+        texture
+    }
+
+    // only uncompressed 24-bit BMPs, of any size
     pub fn load_from_bmp(bmp_data: &Vec<u8>) -> Texture {
+        assert!(bmp_data.len() >= 0x36 && bmp_data[0..2] == *b"BM", "not a BMP file");
         let data_position = u32::from_le_bytes([
             bmp_data[0x0A],
             bmp_data[0x0B],
             bmp_data[0x0C],
             bmp_data[0x0D],
-        ]);
+        ]) as usize;
         // assuming windows BITMAPINFOHEADER, these are i32
         let width = i32::from_le_bytes([
             bmp_data[0x12],
             bmp_data[0x13],
             bmp_data[0x14],
             bmp_data[0x15],
-        ]) as usize;
+        ]);
+        // negative when the rows are stored top down
         let height = i32::from_le_bytes([
             bmp_data[0x16],
             bmp_data[0x17],
             bmp_data[0x18],
             bmp_data[0x19],
-        ]) as usize;
-        let mut has_transparency = false;
+        ]);
+        let bits_per_pixel = u16::from_le_bytes([bmp_data[0x1C], bmp_data[0x1D]]);
+        let compression = u32::from_le_bytes([
+            bmp_data[0x1E],
+            bmp_data[0x1F],
+            bmp_data[0x20],
+            bmp_data[0x21],
+        ]);
+        assert!(bits_per_pixel == 24 && compression == 0, "only uncompressed 24-bit BMPs are supported");
+        assert!(width > 0 && height != 0, "BMP has no pixels");
+        let top_down = height < 0;
+        let width = width as usize;
+        let height = height.unsigned_abs() as usize;
+        // rows are padded to a multiple of 4 bytes
+        let stride = (width * 3 + 3) & !3;
+        assert!(data_position + stride * (height - 1) + width * 3 <= bmp_data.len(), "BMP pixel data is truncated");
         let mut data = Vec::with_capacity(width * height);
-        // step_by 3 assuming 24-bit depth
-        for byte_index in ((data_position as usize)..bmp_data.len()).step_by(3) {
-            if bmp_data[byte_index] == 0x00
-                && bmp_data[byte_index + 1] == 0x00
-                && bmp_data[byte_index + 2] == 0x00
-            {
-                has_transparency = true;
+        for row in 0..height {
+            let row_position = data_position + row * stride;
+            for pixel in bmp_data[row_position..row_position + width * 3].chunks(3) {
+                data.push(u32::from_le_bytes([pixel[0], pixel[1], pixel[2], 0x00]));
+            }
+        }
+        // bottom up rows come out the right way up but mirrored, and top down ones are mirrored to match
+        if top_down {
+            for row in data.chunks_mut(width) {
+                row.reverse();
+            }
+        } else {
+            data.reverse();
+        }
+        Texture::new(width, height, data)
+    }
+
+    fn build_mipmaps(&self) -> Vec<Texture> {
+        let mut mipmaps: Vec<Texture> = Vec::new();
+        while let Some(smaller) = mipmaps.last().unwrap_or(self).half() {
+            mipmaps.push(smaller);
+        }
+        mipmaps
+    }
+
+    // averages each 2x2 block, leaving out transparent texels unless they're most of the block
+    fn half(&self) -> Option<Texture> {
+        if self.width == 1 && self.height == 1 {
+            return None;
+        }
+        let width = self.width.div_ceil(2);
+        let height = self.height.div_ceil(2);
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = [0; 3];
+                let mut opaque = 0;
+                for (source_x, source_y) in [(x * 2, y * 2), (x * 2 + 1, y * 2), (x * 2, y * 2 + 1), (x * 2 + 1, y * 2 + 1)] {
+                    let color = self.data[self.width * source_y.min(self.height - 1) + source_x.min(self.width - 1)];
+                    if (color & 0x00FFFFFF) != 0 {
+                        let bytes = color.to_le_bytes();
+                        for channel in 0..3 {
+                            sum[channel] += bytes[channel] as u32;
+                        }
+                        opaque += 1;
+                    }
+                }
+                data.push(if opaque >= 2 {
+                    u32::from_le_bytes([(sum[0] / opaque) as u8, (sum[1] / opaque) as u8, (sum[2] / opaque) as u8, 0x00])
+                } else {
+                    0
+                });
             }
-            data.push(u32::from_le_bytes([
-                bmp_data[byte_index],
-                bmp_data[byte_index + 1],
-                bmp_data[byte_index + 2],
-                0x00,
-            ]));
         }
-        data.reverse();
-        Texture {
+        Some(Texture {
             width,
             height,
-            has_transparency,
+            has_transparency: self.has_transparency,
             data,
+            indices: Vec::new(),
+            mipmaps: Vec::new(),
+            filter: None,
+        })
+    }
+
+    // the level to use when this many texels fall across each pixel
+    pub fn mip(&self, texels_per_pixel: f64) -> &Texture {
+        match (texels_per_pixel.max(1.0).log2() as usize).checked_sub(1) {
+            None => self,
+            Some(level) => self.mipmaps.get(level).or(self.mipmaps.last()).unwrap_or(self),
         }
     }
+
+    // u runs across and v down the texture, from 0 to 1
+    pub fn offset(&self, u: f64, v: f64, wrap: Wrap) -> usize {
+        self.offset_fixed(fixed(u * self.width as f64), fixed(v * self.height as f64), wrap)
+    }
+
+    // x and y are in texels, in 16.16 fixed point
+    pub fn offset_fixed(&self, x: i64, y: i64, wrap: Wrap) -> usize {
+        self.width * wrap.apply(y >> 16, self.height) + wrap.apply(x >> 16, self.width)
+    }
+
+    // x and y are whole texels, for drawing a texture straight to the screen
+    pub fn texel(&self, x: usize, y: usize) -> u32 {
+        self.data[self.offset_fixed((x as i64) << 16, (y as i64) << 16, Wrap::Clamp)]
+    }
+
+    pub fn sample(&self, u: f64, v: f64, wrap: Wrap) -> u32 {
+        self.data[self.offset(u, v, wrap)]
+    }
+
+    pub fn filtered_fixed(&self, x: i64, y: i64, wrap: Wrap, filter: Filter) -> u32 {
+        match filter {
+            Filter::Nearest => self.data[self.offset_fixed(x, y, wrap)],
+            Filter::Bilinear => self.bilinear(x, y, wrap),
+        }
+    }
+
+    // transparent texels take the colour of the nearest one, so edges don't fade to black
+    fn bilinear(&self, x: i64, y: i64, wrap: Wrap) -> u32 {
+        // measured from texel centres rather than corners
+        let x = x - 0x8000;
+        let y = y - 0x8000;
+        let (x0, x1) = (wrap.apply(x >> 16, self.width), wrap.apply((x >> 16) + 1, self.width));
+        let (y0, y1) = (wrap.apply(y >> 16, self.height), wrap.apply((y >> 16) + 1, self.height));
+        // blending only needs 8 bits of the fraction
+        let weight_x = ((x >> 8) & 0xFF) as u32;
+        let weight_y = ((y >> 8) & 0xFF) as u32;
+        let nearest = self.data[self.width * if weight_y < 128 { y0 } else { y1 } + if weight_x < 128 { x0 } else { x1 }];
+        if (nearest & 0x00FFFFFF) == 0 {
+            return 0;
+        }
+        let texel = |x: usize, y: usize| match self.data[self.width * y + x] {
+            color if (color & 0x00FFFFFF) == 0 => nearest,
+            color => color,
+        };
+        let top = lerp(texel(x0, y0), texel(x1, y0), weight_x);
+        let bottom = lerp(texel(x0, y1), texel(x1, y1), weight_x);
+        lerp(top, bottom, weight_y)
+    }
+}
+
+// red and blue are blended together in one multiply, with green in another
+fn lerp(a: u32, b: u32, weight: u32) -> u32 {
+    let red_blue = (((a & 0x00FF00FF) * (256 - weight) + (b & 0x00FF00FF) * weight) >> 8) & 0x00FF00FF;
+    let green = (((a & 0x0000FF00) * (256 - weight) + (b & 0x0000FF00) * weight) >> 8) & 0x0000FF00;
+    red_blue | green
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // each texel holds its own coordinates, x in the low byte and y above it
+    fn coordinates_texture(width: usize, height: usize) -> Texture {
+        Texture::new(width, height, (0..height).flat_map(|y| (0..width).map(move |x| (y << 8 | x) as u32)).collect())
+    }
+
+    fn bmp(width: usize, height: i32, rows: &[Vec<[u8; 3]>]) -> Vec<u8> {
+        let stride = (width * 3 + 3) & !3;
+        let mut pixels = Vec::new();
+        for row in rows {
+            for pixel in row {
+                pixels.extend_from_slice(pixel);
+            }
+            pixels.resize(pixels.len() + stride - width * 3, 0xEE);
+        }
+        let mut data = b"BM".to_vec();
+        data.extend_from_slice(&(54 + pixels.len() as u32).to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&54u32.to_le_bytes());
+        data.extend_from_slice(&40u32.to_le_bytes());
+        data.extend_from_slice(&(width as i32).to_le_bytes());
+        data.extend_from_slice(&height.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&24u16.to_le_bytes());
+        data.extend_from_slice(&[0; 24]);
+        data.extend_from_slice(&pixels);
+        data
+    }
+
+    #[test]
+    fn skips_bmp_row_padding() {
+        // 3 pixels of 3 bytes pads each row out to 12 bytes
+        let rows = vec![vec![[1, 0, 0], [2, 0, 0], [3, 0, 0]], vec![[4, 0, 0], [5, 0, 0], [6, 0, 0]]];
+        let bottom_up = Texture::load_from_bmp(&bmp(3, 2, &rows));
+        assert_eq!((bottom_up.width, bottom_up.height), (3, 2));
+        assert_eq!(bottom_up.data, vec![6, 5, 4, 3, 2, 1]);
+        let top_down = Texture::load_from_bmp(&bmp(3, -2, &[rows[1].clone(), rows[0].clone()]));
+        assert_eq!(top_down.data, bottom_up.data);
+    }
+
+    #[test]
+    #[should_panic(expected = "truncated")]
+    fn rejects_truncated_bmps() {
+        let mut data = bmp(3, 2, &[vec![[1, 1, 1]; 3], vec![[1, 1, 1]; 3]]);
+        data.truncate(data.len() - 4);
+        Texture::load_from_bmp(&data);
+    }
+
+    #[test]
+    fn samples_tall_textures_by_width() {
+        let texture = coordinates_texture(64, 128);
+        assert_eq!(texture.sample(0.0, 0.0, Wrap::Repeat), 0);
+        assert_eq!(texture.sample(0.5, 0.25, Wrap::Repeat), 32 << 8 | 32);
+        assert_eq!(texture.sample(0.999, 0.999, Wrap::Repeat), 127 << 8 | 63);
+        // a whole wall's height steps through every row exactly once
+        let rows: Vec<u32> = (0..128).map(|y| texture.sample(0.0, (y as f64 + 0.5) / 128.0, Wrap::Repeat) >> 8).collect();
+        assert_eq!(rows, (0..128).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn samples_wide_textures_by_width() {
+        let texture = coordinates_texture(128, 64);
+        assert_eq!(texture.sample(0.75, 0.5, Wrap::Clamp), 32 << 8 | 96);
+        assert_eq!(texture.offset(0.75, 0.5, Wrap::Clamp), 32 * 128 + 96);
+        assert_eq!(texture.texel(96, 32), 32 << 8 | 96);
+    }
+
+    #[test]
+    fn wraps_or_clamps_outside_the_texture() {
+        let texture = coordinates_texture(64, 128);
+        assert_eq!(texture.sample(1.25, -0.25, Wrap::Repeat), 96 << 8 | 16);
+        assert_eq!(texture.sample(1.25, -0.25, Wrap::Clamp), 63);
+        assert_eq!(texture.sample(-3.0, 7.5, Wrap::Clamp), 127 << 8);
+    }
+
+    #[test]
+    fn builds_mipmaps_down_to_one_texel() {
+        let rows = vec![vec![[0x10, 0x20, 0x30]; 5]; 3];
+        let texture = Texture::load_from_bmp(&bmp(5, 3, &rows));
+        let sizes: Vec<(usize, usize)> = texture.mipmaps.iter().map(|mipmap| (mipmap.width, mipmap.height)).collect();
+        assert_eq!(sizes, vec![(3, 2), (2, 1), (1, 1)]);
+        assert!(texture.mipmaps.iter().all(|mipmap| mipmap.data.iter().all(|&color| color == 0x00302010)));
+        assert_eq!(texture.mip(0.5).width, 5);
+        assert_eq!(texture.mip(2.0).width, 3);
+        assert_eq!(texture.mip(1000.0).width, 1);
+    }
+
+    #[test]
+    fn mipmaps_keep_transparency_apart() {
+        let mut texture = coordinates_texture(2, 2);
+        texture.data = vec![0, 0, 0, 0x00808080];
+        assert_eq!(texture.half().unwrap().data, vec![0]);
+        texture.data = vec![0, 0x00404040, 0, 0x00808080];
+        assert_eq!(texture.half().unwrap().data, vec![0x00606060]);
+    }
+
+    #[test]
+    fn bilinear_blends_between_texel_centres() {
+        let mut texture = coordinates_texture(2, 1);
+        texture.data = vec![0x00000000, 0x00FF8040];
+        // on a texel centre it's that texel, and halfway it's half of each
+        assert_eq!(texture.filtered_fixed(fixed(1.5), fixed(0.5), Wrap::Clamp, Filter::Bilinear), 0x00FF8040);
+        assert_eq!(texture.filtered_fixed(fixed(0.5), fixed(0.5), Wrap::Clamp, Filter::Bilinear), 0);
+        texture.data[0] = 0x00010203;
+        assert_eq!(texture.filtered_fixed(fixed(1.0), fixed(0.5), Wrap::Clamp, Filter::Bilinear), 0x00804121);
+        // the transparent texel doesn't darken the one beside it
+        texture.data[0] = 0;
+        assert_eq!(texture.filtered_fixed(fixed(1.2), fixed(0.5), Wrap::Clamp, Filter::Bilinear), 0x00FF8040);
+    }
 }
@@ -0,0 +1,69 @@
+pub const DAMAGE: u32 = 0x00FF0000;
+pub const PICKUP: u32 = 0x00FFD040;
+pub const UNDERWATER: u32 = 0x00108070;
+
+struct Flash {
+    color: u32,
+    strength: f64,
+    duration: f64,
+    age: f64,
+}
+
+// full screen tints laid over the finished frame, like Doom's palette flashes
+pub struct Tints {
+    flashes: Vec<Flash>,
+    pub underwater: bool,
+}
+
+impl Tints {
+    pub fn new() -> Tints {
+        Tints {
+            flashes: Vec::new(),
+            underwater: false,
+        }
+    }
+
+    // strength is how much of the screen the colour covers at first, fading to nothing over duration
+    pub fn flash(&mut self, color: u32, strength: f64, duration: f64) {
+        self.flashes.push(Flash {
+            color,
+            strength: strength.min(1.0),
+            duration,
+            age: 0.0,
+        });
+    }
+
+    pub fn update(&mut self, delta: f64) {
+        for flash in &mut self.flashes {
+            flash.age += delta;
+        }
+        self.flashes.retain(|flash| flash.age < flash.duration);
+    }
+
+    pub fn apply(&self, pixels: &mut [u32]) {
+        // every tint is folded into one premultiplied colour and what's left of the frame, so each pixel is a single blend
+        let mut tint = [0.0; 3];
+        let mut remaining = 1.0;
+        let underwater = if self.underwater { Some((UNDERWATER, 0.35)) } else { None };
+        let flashes = self.flashes.iter().map(|flash| (flash.color, flash.strength * (1.0 - flash.age / flash.duration)));
+        for (color, strength) in underwater.into_iter().chain(flashes) {
+            let bytes = color.to_le_bytes();
+            for channel in 0..3 {
+                tint[channel] = tint[channel] * (1.0 - strength) + bytes[channel] as f64 * strength;
+            }
+            remaining *= 1.0 - strength;
+        }
+        if remaining >= 1.0 {
+            return;
+        }
+        let keep = (remaining * 256.0) as u32;
+        let add = [(tint[0] * 256.0) as u32, (tint[1] * 256.0) as u32, (tint[2] * 256.0) as u32];
+        for pixel in pixels {
+            let mut bytes = pixel.to_le_bytes();
+            for channel in 0..3 {
+                bytes[channel] = ((bytes[channel] as u32 * keep + add[channel]) >> 8) as u8;
+            }
+            *pixel = u32::from_le_bytes(bytes);
+        }
+    }
+}
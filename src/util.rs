@@ -39,6 +39,7 @@ impl Step {
 pub struct Sprite {
     pub position: Vec2<f64>,
     pub texture: Rc<Texture>,
+    // downwards, in wall heights, so it doesn't depend on the resolution
     pub vertical_offset: f64,
     pub scale_factor: Vec2<f64>,
     pub distance_from_camera: f64,
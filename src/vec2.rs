@@ -1,6 +1,6 @@
 use auto_ops::{impl_op_ex, impl_op_ex_commutative};
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct Vec2<T> {
     pub x: T,
     pub y: T,
@@ -0,0 +1,208 @@
+use std::f64::consts::PI;
+use std::rc::Rc;
+
+use crate::camera::Camera;
+use crate::entity::{Entities, EntityId};
+use crate::framebuffer::Framebuffer;
+use crate::map::{Hit, Map};
+use crate::texture::{Texture, Wrap};
+
+pub enum WeaponState {
+    Idle,
+    Firing { time: f64 },
+    Reloading { time: f64 },
+}
+
+pub enum Target {
+    Entity(EntityId),
+    Wall(Hit),
+}
+
+pub struct Weapon {
+    pub damage: f64,
+    pub range: f64,
+    pub fire_interval: f64,
+    pub reload_time: f64,
+    pub magazine_size: usize,
+    pub ammo: usize,
+    pub state: WeaponState,
+    pub impact_texture: Rc<Texture>,
+    idle_texture: Texture,
+    firing_texture: Texture,
+}
+
+impl Weapon {
+    pub fn pistol() -> Weapon {
+        Weapon {
+            damage: 10.0,
+            range: 32.0,
+            fire_interval: 0.3,
+            reload_time: 1.2,
+            magazine_size: 8,
+            ammo: 8,
+            state: WeaponState::Idle,
+            impact_texture: Rc::new(bullet_hole_texture()),
+            idle_texture: pistol_texture(false),
+            firing_texture: pistol_texture(true),
+        }
+    }
+
+    // returns whether a shot went off
+    pub fn trigger(&mut self) -> bool {
+        if let WeaponState::Idle = self.state {
+            if self.ammo > 0 {
+                self.ammo -= 1;
+                self.state = WeaponState::Firing { time: 0.0 };
+                return true;
+            }
+            self.reload();
+        }
+        false
+    }
+
+    pub fn reload(&mut self) {
+        if let WeaponState::Idle = self.state {
+            if self.ammo < self.magazine_size {
+                self.state = WeaponState::Reloading { time: 0.0 };
+            }
+        }
+    }
+
+    pub fn update(&mut self, delta: f64) {
+        let finished = match &mut self.state {
+            WeaponState::Idle => false,
+            WeaponState::Firing { time } => {
+                *time += delta;
+                *time >= self.fire_interval
+            }
+            WeaponState::Reloading { time } => {
+                *time += delta;
+                if *time >= self.reload_time {
+                    self.ammo = self.magazine_size;
+                }
+                *time >= self.reload_time
+            }
+        };
+        if finished {
+            self.state = WeaponState::Idle;
+        }
+    }
+
+    pub fn draw(&self, framebuffer: &mut Framebuffer) {
+        let scale = (framebuffer.height / 200).max(1);
+        let (texture, lowered) = match &self.state {
+            WeaponState::Idle => (&self.idle_texture, 0.0),
+            WeaponState::Firing { time } => {
+                let recoil = (1.0 - time / self.fire_interval) * 4.0;
+                if *time < self.fire_interval / 3.0 {
+                    (&self.firing_texture, recoil)
+                } else {
+                    (&self.idle_texture, recoil)
+                }
+            }
+            // dips out of view and back up again
+            WeaponState::Reloading { time } => (
+                &self.idle_texture,
+                (time / self.reload_time * PI).sin() * self.idle_texture.height as f64 * 0.7,
+            ),
+        };
+        framebuffer.draw_overlay(
+            texture,
+            (framebuffer.width as i32 - (texture.width * scale) as i32) / 2,
+            (framebuffer.height as i32 - (texture.height * scale) as i32) + (lowered * scale as f64) as i32,
+            scale,
+        );
+
+        let centre_x = framebuffer.width / 2;
+        let centre_y = framebuffer.height / 2;
+        // past the left or top edge wraps round to a huge coordinate, which set_pixel skips
+        for offset in 2..6 {
+            framebuffer.set_pixel(centre_x.wrapping_sub(offset), centre_y, 0x00FFFFFF);
+            framebuffer.set_pixel(centre_x + offset, centre_y, 0x00FFFFFF);
+            framebuffer.set_pixel(centre_x, centre_y.wrapping_sub(offset), 0x00FFFFFF);
+            framebuffer.set_pixel(centre_x, centre_y + offset, 0x00FFFFFF);
+        }
+    }
+}
+
+// the first thing in the centre column: walls through the map, and sprites as camera facing quads
+pub fn hitscan(camera: &Camera, world: &Map, entities: &Entities, range: f64) -> Option<Target> {
+    let wall = world.cast_ray(&camera.position, &camera.direction, range);
+    let mut nearest = match &wall {
+        Some(hit) => hit.distance,
+        None => range,
+    };
+    let direction = &camera.direction / camera.direction.length();
+    let mut target = None;
+    for id in entities.near(&camera.position, range) {
+        let entity = entities.get(id).unwrap();
+        let sprite = match &entity.sprite {
+            Some(sprite) => sprite,
+            None => continue,
+        };
+        let rel_position = &entity.position - &camera.position;
+        let along = rel_position.x * direction.x + rel_position.y * direction.y;
+        let across = direction.y * rel_position.x - direction.x * rel_position.y;
+        if along <= 0.0 || along >= nearest || across.abs() > sprite.scale_factor.x / 2.0 {
+            continue;
+        }
+        // shots pass through fully transparent columns, like either side of a barrel
+        let u = across / sprite.scale_factor.x + 0.5;
+        if (0..sprite.texture.height).all(|tex_y| {
+            let v = (tex_y as f64 + 0.5) / sprite.texture.height as f64;
+            (sprite.texture.sample(u, v, Wrap::Clamp) & 0x00FFFFFF) == 0
+        }) {
+            continue;
+        }
+        nearest = along;
+        target = Some(Target::Entity(id));
+    }
+    target.or_else(|| wall.map(Target::Wall))
+}
+
+// there's no weapon art in res/, so the pistol is drawn here
+fn pistol_texture(muzzle_flash: bool) -> Texture {
+    let size = 64;
+    let mut data = vec![0; size * size];
+    for y in 0..size {
+        for x in 0..size {
+            let flash_distance = ((x as f64 - 31.5).powi(2) + (y as f64 - 4.0).powi(2)).sqrt();
+            data[y * size + x] = if (29..35).contains(&x) && (6..10).contains(&y) {
+                0x00303030
+            } else if (26..38).contains(&x) && (10..46).contains(&y) {
+                if x < 29 {
+                    0x008A8A8A
+                } else {
+                    0x005A5A5A
+                }
+            } else if (22..42).contains(&x) && (42..64).contains(&y) {
+                0x003A3A3A
+            } else if muzzle_flash && flash_distance < 4.0 {
+                0x00FFFFCC
+            } else if muzzle_flash && flash_distance < 9.0 {
+                0x00FFAA22
+            } else {
+                0
+            };
+        }
+    }
+    Texture::new(size, size, data)
+}
+
+fn bullet_hole_texture() -> Texture {
+    let size = 8;
+    let mut data = vec![0; size * size];
+    for y in 0..size {
+        for x in 0..size {
+            let distance = ((x as f64 - 3.5).powi(2) + (y as f64 - 3.5).powi(2)).sqrt();
+            data[y * size + x] = if distance < 2.0 {
+                0x00101010
+            } else if distance < 3.5 {
+                0x00383838
+            } else {
+                0
+            };
+        }
+    }
+    Texture::new(size, size, data)
+}